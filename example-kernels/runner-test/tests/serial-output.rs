@@ -0,0 +1,38 @@
+#![no_std]
+#![no_main]
+
+use core::{fmt::Write, panic::PanicInfo};
+use runner_test::{exit_qemu, ExitCode};
+use x86_64::instructions::port::Port;
+
+/// Writes to the first serial port, which QEMU forwards to stdio.
+struct Serial;
+
+impl Write for Serial {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut port = Port::<u8>::new(0x3f8);
+        for byte in s.bytes() {
+            unsafe { port.write(byte) };
+        }
+        Ok(())
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    let _ = writeln!(Serial, "serial-output: booted");
+    let _ = writeln!(Serial, "serial-output: _start is at {:#x}", _start as *const () as usize);
+    let _ = writeln!(Serial, "serial-output: done");
+    unsafe {
+        exit_qemu(ExitCode::Success);
+    }
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    unsafe {
+        exit_qemu(ExitCode::Failed);
+    }
+    loop {}
+}
@@ -1,3 +1,4 @@
+use crate::plan::PlanFormat;
 use anyhow::{anyhow, Context, Result};
 use std::{
     mem,
@@ -21,8 +22,11 @@ impl BuildCommand {
         A: Iterator<Item = String>,
     {
         let mut manifest_path: Option<PathBuf> = None;
+        let mut profile: Option<String> = None;
+        let mut out_dir: Option<PathBuf> = None;
         let mut cargo_args = Vec::new();
         let mut quiet = false;
+        let mut dry_run = None;
         {
             fn set<T>(arg: &mut Option<T>, value: Option<T>) -> Result<()> {
                 let previous = mem::replace(arg, value);
@@ -44,6 +48,12 @@ impl BuildCommand {
                     "--quiet" => {
                         quiet = true;
                     }
+                    "--dry-run" => {
+                        dry_run = Some(PlanFormat::Text);
+                    }
+                    _ if arg.starts_with("--dry-run=") => {
+                        dry_run = Some(arg.trim_start_matches("--dry-run=").parse()?);
+                    }
                     "--manifest-path" => {
                         let next = arg_iter.next();
                         set(
@@ -65,6 +75,36 @@ impl BuildCommand {
                         set(&mut manifest_path, Some(path))?;
                         cargo_args.push(arg);
                     }
+                    "--out-dir" => {
+                        let next = arg_iter
+                            .next()
+                            .ok_or_else(|| anyhow!("--out-dir requires a value"))?;
+                        set(&mut out_dir, Some(PathBuf::from(next)))?;
+                    }
+                    _ if arg.starts_with("--out-dir=") => {
+                        let path = PathBuf::from(arg.trim_start_matches("--out-dir="));
+                        set(&mut out_dir, Some(path))?;
+                    }
+                    "--release" | "-r" => {
+                        set(&mut profile, Some("release".into()))
+                            .context("--release conflicts with --profile")?;
+                        cargo_args.push(arg);
+                    }
+                    "--profile" => {
+                        let next = arg_iter
+                            .next()
+                            .ok_or_else(|| anyhow!("--profile requires a value"))?;
+                        set(&mut profile, Some(next.clone()))
+                            .context("multiple --profile or --release arguments")?;
+                        cargo_args.push(arg);
+                        cargo_args.push(next);
+                    }
+                    _ if arg.starts_with("--profile=") => {
+                        let name = arg.trim_start_matches("--profile=").to_owned();
+                        set(&mut profile, Some(name))
+                            .context("multiple --profile or --release arguments")?;
+                        cargo_args.push(arg);
+                    }
                     _ => {
                         cargo_args.push(arg);
                     }
@@ -74,8 +114,11 @@ impl BuildCommand {
 
         Ok(BuildCommand::Build(BuildArgs {
             manifest_path,
+            profile,
+            out_dir,
             cargo_args,
             quiet,
+            dry_run,
         }))
     }
 }
@@ -85,10 +128,17 @@ impl BuildCommand {
 pub struct BuildArgs {
     /// The manifest path (also present in `cargo_args`).
     manifest_path: Option<PathBuf>,
+    /// The cargo profile selected through `--profile` or `--release`/`-r` (also present in
+    /// `cargo_args`).
+    profile: Option<String>,
+    /// The directory in which the disk images should be placed (not passed to cargo).
+    out_dir: Option<PathBuf>,
     /// All arguments that are passed to cargo.
     cargo_args: Vec<String>,
     /// Suppress any output to stdout.
     quiet: bool,
+    /// Print the commands that would be executed instead of executing them.
+    dry_run: Option<PlanFormat>,
 }
 
 impl BuildArgs {
@@ -97,13 +147,28 @@ impl BuildArgs {
         self.manifest_path.as_deref()
     }
 
+    /// The cargo profile that was selected through `--profile` or `--release`, if any.
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    /// The value of the `--out-dir` argument, if any.
+    pub fn out_dir(&self) -> Option<&Path> {
+        self.out_dir.as_deref()
+    }
+
     /// Arguments that should be forwarded to `cargo build`.
     pub fn cargo_args(&self) -> &[String] {
-        &self.cargo_args.as_ref()
+        self.cargo_args.as_ref()
     }
 
     /// Whether a `--quiet` flag was passed.
     pub fn quiet(&self) -> bool {
         self.quiet
     }
+
+    /// The output format of the `--dry-run` flag, if it was passed.
+    pub fn dry_run(&self) -> Option<PlanFormat> {
+        self.dry_run
+    }
 }
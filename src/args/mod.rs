@@ -2,6 +2,10 @@
 
 pub use build::*;
 pub use runner::*;
+pub use setup::*;
+pub use test::*;
 
 mod build;
 mod runner;
+mod setup;
+mod test;
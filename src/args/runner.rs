@@ -1,5 +1,6 @@
-use anyhow::{anyhow, Result};
-use std::path::PathBuf;
+use crate::plan::PlanFormat;
+use anyhow::{anyhow, Error, Result};
+use std::{path::PathBuf, str::FromStr};
 
 /// Internal representation of the `bootimage runner` command.
 pub enum RunnerCommand {
@@ -19,13 +20,44 @@ impl RunnerCommand {
     {
         let mut executable = None;
         let mut quiet = false;
+        let mut dry_run = None;
+        let mut junit = None;
+        let mut test_format = None;
+        let mut bless = false;
+        let mut retries = None;
+        let mut images = None;
         let mut runner_args = None;
 
         let mut arg_iter = args.fuse();
 
         loop {
             if executable.is_some() {
-                let args: Vec<_> = arg_iter.collect();
+                let mut args = Vec::new();
+                // `cargo test -- --format json -Z unstable-options` passes the libtest
+                // arguments after the executable, so they are filtered out of the QEMU
+                // arguments
+                while let Some(arg) = arg_iter.next() {
+                    match arg.as_str() {
+                        "--format" => {
+                            let format = arg_iter
+                                .next()
+                                .ok_or_else(|| anyhow!("expected a format after `--format`"))?;
+                            test_format = Some(format.parse()?);
+                        }
+                        arg if arg.starts_with("--format=") => {
+                            test_format = Some(arg.trim_start_matches("--format=").parse()?);
+                        }
+                        "-Zunstable-options" => {}
+                        "-Z" => {
+                            let next = arg_iter.next();
+                            if next.as_deref() != Some("unstable-options") {
+                                args.push(arg);
+                                args.extend(next);
+                            }
+                        }
+                        _ => args.push(arg),
+                    }
+                }
                 if !args.is_empty() {
                     runner_args = Some(args);
                 }
@@ -45,6 +77,51 @@ impl RunnerCommand {
                 "--quiet" => {
                     quiet = true;
                 }
+                "--dry-run" => {
+                    dry_run = Some(PlanFormat::Text);
+                }
+                arg if arg.starts_with("--dry-run=") => {
+                    dry_run = Some(arg.trim_start_matches("--dry-run=").parse()?);
+                }
+                "--junit" => {
+                    let path = arg_iter
+                        .next()
+                        .ok_or_else(|| anyhow!("expected a path after `--junit`"))?;
+                    junit = Some(PathBuf::from(path));
+                }
+                arg if arg.starts_with("--junit=") => {
+                    junit = Some(PathBuf::from(arg.trim_start_matches("--junit=")));
+                }
+                "--format" => {
+                    let format = arg_iter
+                        .next()
+                        .ok_or_else(|| anyhow!("expected a format after `--format`"))?;
+                    test_format = Some(format.parse()?);
+                }
+                arg if arg.starts_with("--format=") => {
+                    test_format = Some(arg.trim_start_matches("--format=").parse()?);
+                }
+                "--bless" => {
+                    bless = true;
+                }
+                "--retries" => {
+                    let value = arg_iter
+                        .next()
+                        .ok_or_else(|| anyhow!("expected a number after `--retries`"))?;
+                    retries = Some(parse_retries(&value)?);
+                }
+                arg if arg.starts_with("--retries=") => {
+                    retries = Some(parse_retries(arg.trim_start_matches("--retries="))?);
+                }
+                "--images" => {
+                    let path = arg_iter
+                        .next()
+                        .ok_or_else(|| anyhow!("expected a path after `--images`"))?;
+                    images = Some(PathBuf::from(path));
+                }
+                arg if arg.starts_with("--images=") => {
+                    images = Some(PathBuf::from(arg.trim_start_matches("--images=")));
+                }
                 exe => {
                     executable = Some(PathBuf::from(exe));
                 }
@@ -55,11 +132,23 @@ impl RunnerCommand {
             executable: executable
                 .ok_or_else(|| anyhow!("excepted path to kernel executable as first argument"))?,
             quiet,
+            dry_run,
+            junit,
+            test_format,
+            bless,
+            retries,
+            images,
             runner_args,
         }))
     }
 }
 
+fn parse_retries(value: &str) -> Result<u32> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("invalid number of retries `{}`", value))
+}
+
 /// Arguments for the `bootimage runner` command
 #[derive(Debug, Clone)]
 pub struct RunnerArgs {
@@ -67,6 +156,48 @@ pub struct RunnerArgs {
     pub executable: PathBuf,
     /// Suppress any output to stdout.
     pub quiet: bool,
+    /// Print the commands that would be executed in the given format instead of executing them.
+    pub dry_run: Option<PlanFormat>,
+    /// Write the results of test executables to a JUnit XML report at the given path.
+    pub junit: Option<PathBuf>,
+    /// The output format for the results of test executables.
+    ///
+    /// Set through `--format` before or after the executable, like the argument of libtest.
+    pub test_format: Option<TestFormat>,
+    /// Update the expected output files of tests instead of comparing against them.
+    pub bless: bool,
+    /// How often a failed test executable is rerun, overriding the `test-retries` config key.
+    pub retries: Option<u32>,
+    /// The `images.json` index of `cargo bootimage` in which the executable is recorded.
+    ///
+    /// If set, the executable is a disk image that was created by `cargo bootimage`. It is
+    /// run directly, with the kind and test settings that are recorded in the index.
+    pub images: Option<PathBuf>,
     /// Additional arguments passed to the runner
     pub runner_args: Option<Vec<String>>,
 }
+
+/// The output format for the results of test executables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestFormat {
+    /// Human-readable output with a summary of the test cases
+    Pretty,
+    /// The JSON event stream of libtest (`--format json`)
+    Json,
+}
+
+impl FromStr for TestFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            // the summary of `bootimage runner` has no terse variant
+            "pretty" | "terse" => Ok(TestFormat::Pretty),
+            "json" => Ok(TestFormat::Json),
+            other => Err(anyhow!(
+                "invalid test format `{}` (expected `pretty`, `terse`, or `json`)",
+                other
+            )),
+        }
+    }
+}
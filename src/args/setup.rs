@@ -0,0 +1,101 @@
+use anyhow::{anyhow, Context, Result};
+use std::{
+    mem,
+    path::{Path, PathBuf},
+};
+
+/// Internal representation of the `bootimage setup` command.
+pub enum SetupCommand {
+    /// A normal invocation of `bootimage setup` (i.e. no `--help` or `--version`)
+    Setup(SetupArgs),
+    /// A command containing `--version`
+    Version,
+    /// A command containing `--help`
+    Help,
+}
+
+impl SetupCommand {
+    /// Parse the given argument set into the internal representation.
+    pub fn parse_args<A>(args: A) -> Result<Self>
+    where
+        A: Iterator<Item = String>,
+    {
+        let mut manifest_path: Option<PathBuf> = None;
+        let mut target: Option<String> = None;
+        let mut quiet = false;
+        {
+            fn set<T>(arg: &mut Option<T>, value: Option<T>) -> Result<()> {
+                let previous = mem::replace(arg, value);
+                if previous.is_some() {
+                    return Err(anyhow!("multiple arguments of same type provided"));
+                }
+                Ok(())
+            }
+
+            let mut arg_iter = args;
+            while let Some(arg) = arg_iter.next() {
+                match arg.as_ref() {
+                    "--help" | "-h" => {
+                        return Ok(SetupCommand::Help);
+                    }
+                    "--version" => {
+                        return Ok(SetupCommand::Version);
+                    }
+                    "--quiet" => {
+                        quiet = true;
+                    }
+                    "--manifest-path" => {
+                        let path = arg_iter
+                            .next()
+                            .ok_or_else(|| anyhow!("--manifest-path requires a value"))?;
+                        let path = Path::new(&path)
+                            .canonicalize()
+                            .context("--manifest-path invalid")?;
+                        set(&mut manifest_path, Some(path))?;
+                    }
+                    _ if arg.starts_with("--manifest-path=") => {
+                        let path = Path::new(arg.trim_start_matches("--manifest-path="))
+                            .canonicalize()
+                            .context("--manifest-path invalid")?;
+                        set(&mut manifest_path, Some(path))?;
+                    }
+                    "--target" => {
+                        let value = arg_iter
+                            .next()
+                            .ok_or_else(|| anyhow!("--target requires a value"))?;
+                        set(&mut target, Some(value))?;
+                    }
+                    _ if arg.starts_with("--target=") => {
+                        set(
+                            &mut target,
+                            Some(arg.trim_start_matches("--target=").to_owned()),
+                        )?;
+                    }
+                    other => {
+                        return Err(anyhow!(
+                            "unexpected argument `{}` for `bootimage setup`",
+                            other
+                        ));
+                    }
+                }
+            }
+        }
+
+        Ok(SetupCommand::Setup(SetupArgs {
+            manifest_path,
+            target,
+            quiet,
+        }))
+    }
+}
+
+/// Arguments for the `bootimage setup` command
+#[derive(Debug, Clone)]
+pub struct SetupArgs {
+    /// The value of the `--manifest-path` argument, if any.
+    pub manifest_path: Option<PathBuf>,
+    /// The compilation target that should be written to the `build.target` key.
+    pub target: Option<String>,
+    /// Suppress any output to stdout.
+    pub quiet: bool,
+}
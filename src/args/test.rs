@@ -0,0 +1,242 @@
+use anyhow::{anyhow, Context, Result};
+use std::{
+    mem,
+    path::{Path, PathBuf},
+};
+
+/// Internal representation of the `bootimage test` command.
+pub enum TestCommand {
+    /// A normal invocation of `bootimage test` (i.e. no `--help` or `--version`)
+    Test(TestArgs),
+    /// A command containing `--version`
+    Version,
+    /// A command containing `--help`
+    Help,
+}
+
+impl TestCommand {
+    /// Parse the given argument set into the internal representation.
+    pub fn parse_args<A>(args: A) -> Result<Self>
+    where
+        A: Iterator<Item = String>,
+    {
+        let mut manifest_path: Option<PathBuf> = None;
+        let mut profile: Option<String> = None;
+        let mut jobs: Option<usize> = None;
+        let mut junit: Option<PathBuf> = None;
+        let mut retries: Option<u32> = None;
+        let mut fail_fast = false;
+        let mut quiet = false;
+        let mut cargo_args = Vec::new();
+        let mut filters = Vec::new();
+        {
+            fn set<T>(arg: &mut Option<T>, value: Option<T>) -> Result<()> {
+                let previous = mem::replace(arg, value);
+                if previous.is_some() {
+                    return Err(anyhow!("multiple arguments of same type provided"));
+                }
+                Ok(())
+            }
+            fn parse_jobs(value: &str) -> Result<usize> {
+                match value.parse() {
+                    Ok(0) | Err(_) => Err(anyhow!("invalid number of jobs `{}`", value)),
+                    Ok(jobs) => Ok(jobs),
+                }
+            }
+            fn parse_retries(value: &str) -> Result<u32> {
+                value
+                    .parse()
+                    .map_err(|_| anyhow!("invalid number of retries `{}`", value))
+            }
+
+            let mut arg_iter = args;
+            while let Some(arg) = arg_iter.next() {
+                match arg.as_ref() {
+                    "--help" | "-h" => {
+                        return Ok(TestCommand::Help);
+                    }
+                    "--version" => {
+                        return Ok(TestCommand::Version);
+                    }
+                    "--quiet" => {
+                        quiet = true;
+                    }
+                    "--fail-fast" => {
+                        fail_fast = true;
+                    }
+                    "--jobs" | "-j" => {
+                        let value = arg_iter
+                            .next()
+                            .ok_or_else(|| anyhow!("--jobs requires a value"))?;
+                        set(&mut jobs, Some(parse_jobs(&value)?))?;
+                    }
+                    _ if arg.starts_with("--jobs=") => {
+                        let jobs_value = parse_jobs(arg.trim_start_matches("--jobs="))?;
+                        set(&mut jobs, Some(jobs_value))?;
+                    }
+                    "--junit" => {
+                        let path = arg_iter
+                            .next()
+                            .ok_or_else(|| anyhow!("--junit requires a value"))?;
+                        set(&mut junit, Some(PathBuf::from(path)))?;
+                    }
+                    _ if arg.starts_with("--junit=") => {
+                        let path = PathBuf::from(arg.trim_start_matches("--junit="));
+                        set(&mut junit, Some(path))?;
+                    }
+                    "--retries" => {
+                        let value = arg_iter
+                            .next()
+                            .ok_or_else(|| anyhow!("--retries requires a value"))?;
+                        set(&mut retries, Some(parse_retries(&value)?))?;
+                    }
+                    _ if arg.starts_with("--retries=") => {
+                        let value = parse_retries(arg.trim_start_matches("--retries="))?;
+                        set(&mut retries, Some(value))?;
+                    }
+                    "--manifest-path" => {
+                        let next = arg_iter.next();
+                        set(
+                            &mut manifest_path,
+                            next.as_ref()
+                                .map(|p| Path::new(&p).canonicalize())
+                                .transpose()
+                                .context("--manifest-path invalid")?,
+                        )?;
+                        cargo_args.push(arg);
+                        if let Some(next) = next {
+                            cargo_args.push(next);
+                        }
+                    }
+                    _ if arg.starts_with("--manifest-path=") => {
+                        let path = Path::new(arg.trim_start_matches("--manifest-path="))
+                            .canonicalize()
+                            .context("--manifest-path invalid")?;
+                        set(&mut manifest_path, Some(path))?;
+                        cargo_args.push(arg);
+                    }
+                    "--release" | "-r" => {
+                        set(&mut profile, Some("release".into()))
+                            .context("--release conflicts with --profile")?;
+                        cargo_args.push(arg);
+                    }
+                    "--profile" => {
+                        let next = arg_iter
+                            .next()
+                            .ok_or_else(|| anyhow!("--profile requires a value"))?;
+                        set(&mut profile, Some(next.clone()))
+                            .context("multiple --profile or --release arguments")?;
+                        cargo_args.push(arg);
+                        cargo_args.push(next);
+                    }
+                    _ if arg.starts_with("--profile=") => {
+                        let name = arg.trim_start_matches("--profile=").to_owned();
+                        set(&mut profile, Some(name))
+                            .context("multiple --profile or --release arguments")?;
+                        cargo_args.push(arg);
+                    }
+                    "--" => {
+                        filters.extend(arg_iter.by_ref());
+                    }
+                    _ => {
+                        cargo_args.push(arg);
+                    }
+                };
+            }
+        }
+
+        Ok(TestCommand::Test(TestArgs {
+            manifest_path,
+            profile,
+            jobs,
+            junit,
+            retries,
+            fail_fast,
+            quiet,
+            cargo_args,
+            filters,
+        }))
+    }
+}
+
+/// Arguments passed to `bootimage test`.
+#[derive(Debug, Clone)]
+pub struct TestArgs {
+    /// The manifest path (also present in `cargo_args`).
+    manifest_path: Option<PathBuf>,
+    /// The cargo profile selected through `--profile` or `--release`/`-r` (also present in
+    /// `cargo_args`).
+    profile: Option<String>,
+    /// The maximum number of QEMU instances that run at the same time.
+    jobs: Option<usize>,
+    /// The path of a JUnit XML report for the results.
+    junit: Option<PathBuf>,
+    /// How often a failed test executable is rerun.
+    retries: Option<u32>,
+    /// Stop starting new test executables after the first failure.
+    fail_fast: bool,
+    /// Suppress the output of the build commands.
+    quiet: bool,
+    /// All arguments that are passed to cargo.
+    cargo_args: Vec<String>,
+    /// Only test executables whose name contains one of these filters are run.
+    filters: Vec<String>,
+}
+
+impl TestArgs {
+    /// The value of the `--manifest-path` argument, if any.
+    pub fn manifest_path(&self) -> Option<&Path> {
+        self.manifest_path.as_deref()
+    }
+
+    /// The cargo profile that was selected through `--profile` or `--release`, if any.
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    /// The value of the `--jobs` argument, if any.
+    pub fn jobs(&self) -> Option<usize> {
+        self.jobs
+    }
+
+    /// The value of the `--junit` argument, if any.
+    pub fn junit(&self) -> Option<&Path> {
+        self.junit.as_deref()
+    }
+
+    /// The value of the `--retries` argument, if any.
+    pub fn retries(&self) -> Option<u32> {
+        self.retries
+    }
+
+    /// Whether a `--fail-fast` flag was passed.
+    pub fn fail_fast(&self) -> bool {
+        self.fail_fast
+    }
+
+    /// Whether a `--quiet` flag was passed.
+    pub fn quiet(&self) -> bool {
+        self.quiet
+    }
+
+    /// Arguments that should be forwarded to the cargo build command.
+    pub fn cargo_args(&self) -> &[String] {
+        self.cargo_args.as_ref()
+    }
+
+    /// The name filters given after `--`.
+    pub fn filters(&self) -> &[String] {
+        self.filters.as_ref()
+    }
+
+    /// Returns whether the test executable with the given name matches the filters.
+    ///
+    /// All test executables match if no filters are given.
+    pub fn matches(&self, name: &str) -> bool {
+        self.filters.is_empty()
+            || self
+                .filters
+                .iter()
+                .any(|filter| name.contains(filter.as_str()))
+    }
+}
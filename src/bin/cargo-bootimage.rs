@@ -1,11 +1,15 @@
 use anyhow::{anyhow, Context, Result};
 use bootimage::{
     args::{BuildArgs, BuildCommand},
-    builder::Builder,
-    config, help,
+    builder::{self, Builder},
+    config::{self, ImageNameVars},
+    help,
+    images::{self, ImageRecord},
+    plan::{Plan, PlanFormat},
 };
 use std::{
-    env,
+    collections::BTreeMap,
+    env, fs,
     path::{Path, PathBuf},
 };
 
@@ -44,22 +48,29 @@ pub fn main() -> Result<()> {
 fn build(args: BuildArgs) -> Result<()> {
     let mut builder = Builder::new(args.manifest_path().map(PathBuf::from))?;
     let config = config::read_config(builder.manifest_path())?;
+    builder.set_log_retention(config.log_retention);
     let quiet = args.quiet();
 
-    let executables = builder.build_kernel(&args.cargo_args(), &config, quiet)?;
+    if let Some(format) = args.dry_run() {
+        return dry_run(&mut builder, &args, &config, format);
+    }
+
+    let executables = builder.build_kernel(args.cargo_args(), &config, quiet)?;
     if executables.is_empty() {
         return Err(anyhow!("no executables built"));
     }
 
+    let target_dir = builder
+        .target_dir()
+        .context("Failed to run cargo metadata to find out target directory")?
+        .to_owned();
+    let mut images = Vec::new();
+    let mut summary = Vec::new();
     for executable in executables {
         let out_dir = executable
+            .path
             .parent()
             .ok_or_else(|| anyhow!("executable has no parent path"))?;
-        let bin_name = &executable
-            .file_stem()
-            .ok_or_else(|| anyhow!("executable has no file stem"))?
-            .to_str()
-            .ok_or_else(|| anyhow!("executable file stem not valid utf8"))?;
 
         // We don't have access to a CARGO_MANIFEST_DIR environment variable
         // here because `cargo bootimage` is started directly by the user. We
@@ -67,25 +78,377 @@ fn build(args: BuildArgs) -> Result<()> {
         // executables ourselves. For workspace projects, this can be a
         // different Cargo.toml than the Cargo.toml in the current directory.
         //
-        // To retrieve the correct Cargo.toml path, we look for the binary name
-        // in the `cargo metadata` output and then get the manifest path from
-        // the corresponding package.
+        // To retrieve the correct Cargo.toml path, we look for the package id
+        // of the executable in the `cargo metadata` output and then get the
+        // manifest path from the corresponding package.
         let kernel_package = builder
-            .kernel_package_for_bin(bin_name)
+            .kernel_package(&executable)
             .context("Failed to run cargo metadata to find out kernel manifest path")?
-            .ok_or_else(|| anyhow!("Failed to find kernel binary in cargo metadata output"))?;
-        let kernel_manifest_path = &kernel_package.manifest_path.to_owned();
+            .ok_or_else(|| {
+                anyhow!(
+                    "Failed to find package `{}` in cargo metadata output",
+                    executable.package_id
+                )
+            })?;
+        let kernel_package = kernel_package.clone();
+        let kernel_manifest_path = &kernel_package.manifest_path;
+        let profile = args
+            .profile()
+            .map(String::from)
+            .or_else(|| builder::profile_from_executable_path(&executable.path))
+            .unwrap_or_else(|| "dev".into());
+        let mut kernel_config = config::read_config(kernel_manifest_path)?.for_profile(&profile);
+        if executable.test || executable.target_kind == "test" {
+            kernel_config = kernel_config.for_test(&executable.target_name);
+        }
+        if let Some(out_dir) = args.out_dir() {
+            kernel_config.out_dir = Some(resolve_out_dir(out_dir, kernel_manifest_path)?);
+        }
+
+        // Workspace builds (e.g. with `--workspace`) also build packages that are no kernels,
+        // so we skip all packages without a bootloader.
+        if !builder::has_bootloader_dependency(&kernel_package)
+            && kernel_config.bootloader_prebuilt.is_none()
+        {
+            if !quiet {
+                println!(
+                    "Skipping {} `{}` of package `{}` (no bootloader dependency)",
+                    executable.target_kind, executable.target_name, kernel_package.name
+                );
+            }
+            continue;
+        }
 
-        let bootimage_path = out_dir.join(format!("bootimage-{}.bin", bin_name));
-        builder.create_bootimage(kernel_manifest_path, &executable, &bootimage_path, quiet)?;
+        let image_name_vars = ImageNameVars {
+            package: kernel_package.name.clone(),
+            bin: executable.image_bin_name(),
+            version: kernel_package.version.to_string(),
+            target: builder::target_from_executable_path(&executable.path, &target_dir)
+                .unwrap_or_else(|| "unknown".into()),
+            profile: profile.clone(),
+        };
+        let bootimage_path = kernel_config.image_path(&image_name_vars, out_dir);
+        if let Some(parent) = bootimage_path.parent() {
+            fs::create_dir_all(parent).with_context(|| {
+                format!("Failed to create output directory `{}`", parent.display())
+            })?;
+        }
+        // log the bootloader build of each executable to its own directory
+        builder.set_log_name(&image_name_vars.bin);
+        builder.create_bootimage(
+            kernel_manifest_path,
+            &executable.path,
+            &bootimage_path,
+            &kernel_config,
+            quiet,
+        )?;
         if !args.quiet() {
+            let kind = if executable.test {
+                format!("{} (test)", executable.target_kind)
+            } else {
+                executable.target_kind.clone()
+            };
             println!(
-                "Created bootimage for `{}` at `{}`",
-                bin_name,
+                "Created bootimage for {} `{}` at `{}`",
+                kind,
+                executable.target_name,
                 bootimage_path.display()
             );
         }
+        summary.push((
+            kernel_package.name.clone(),
+            executable.image_bin_name(),
+            bootimage_path.clone(),
+        ));
+        images.push(ImageRecord::new(
+            &bootimage_path,
+            &executable,
+            kernel_manifest_path,
+            &profile,
+            &kernel_config,
+        ));
+    }
+
+    if summary.is_empty() {
+        return Err(anyhow!("no executables with a bootloader dependency built"));
+    }
+    if !quiet && summary.len() > 1 {
+        print_summary(&mut summary);
+    }
+
+    // Record all created images together with their kind, so that the images can be
+    // archived and later run with the right settings (e.g. `test-args` for tests). Each
+    // image is recorded in the index of the profile that its executable was built with.
+    let mut images_by_profile: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for record in images {
+        images_by_profile
+            .entry(record.profile.clone())
+            .or_default()
+            .push(record);
+    }
+    for (profile, records) in images_by_profile {
+        let index_path = images::index_path(&target_dir, &profile);
+        images::update_index(&index_path, &records)?;
     }
 
     Ok(())
 }
+
+/// Resolves a relative `--out-dir` argument against the directory of the kernel's Cargo.toml,
+/// in the same way as the `out-dir` config key.
+fn resolve_out_dir(out_dir: &Path, kernel_manifest_path: &Path) -> Result<PathBuf> {
+    let manifest_dir = kernel_manifest_path
+        .parent()
+        .ok_or_else(|| anyhow!("kernel manifest path has no parent directory"))?;
+    Ok(manifest_dir.join(out_dir))
+}
+
+/// Prints a table of the created images, grouped by package.
+fn print_summary(summary: &mut [(String, String, PathBuf)]) {
+    summary.sort();
+    let package_width = summary.iter().map(|(p, _, _)| p.len()).max().unwrap_or(0);
+    let bin_width = summary.iter().map(|(_, b, _)| b.len()).max().unwrap_or(0);
+    let package_width = package_width.max("package".len());
+    let bin_width = bin_width.max("executable".len());
+
+    println!();
+    println!(
+        "{:pw$}  {:bw$}  image",
+        "package",
+        "executable",
+        pw = package_width,
+        bw = bin_width
+    );
+    let mut previous_package = None;
+    for (package, bin, image) in summary.iter() {
+        // only print the package name in the first row of each package
+        let package_column = if previous_package == Some(package) {
+            ""
+        } else {
+            package.as_str()
+        };
+        previous_package = Some(package);
+        println!(
+            "{:pw$}  {:bw$}  {}",
+            package_column,
+            bin,
+            image.display(),
+            pw = package_width,
+            bw = bin_width
+        );
+    }
+}
+
+/// Prints the commands that `build` would execute, without executing them.
+///
+/// The paths of the kernel executables are only known after the kernel build, so they are
+/// predicted from the targets of the kernel packages that the cargo arguments select.
+fn dry_run(
+    builder: &mut Builder,
+    args: &BuildArgs,
+    config: &config::Config,
+    format: PlanFormat,
+) -> Result<()> {
+    let mut plan = Plan::default();
+    plan.commands
+        .extend(builder.plan_build_kernel(args.cargo_args(), config));
+    plan.notes.push(
+        "the paths of the kernel and bootloader executables are predicted, the actual \
+         paths are only known after the build"
+            .into(),
+    );
+    let selection = selected_targets(args.cargo_args())?;
+    if selection.iter().any(|(kind, _)| *kind == "test") {
+        plan.notes
+            .push("test executables have a hash suffix, which is shown as `<hash>`".into());
+    }
+
+    let profile = args.profile().unwrap_or("dev").to_owned();
+    let profile_dir = builder::profile_dir_name(&profile);
+    let mut cargo_args = args.cargo_args().iter();
+    let mut target = None;
+    while let Some(arg) = cargo_args.next() {
+        if arg == "--target" {
+            target = cargo_args.next().cloned();
+        } else if arg.starts_with("--target=") {
+            target = Some(arg.trim_start_matches("--target=").to_owned());
+        }
+    }
+    let target_name = target.as_ref().map(|target| {
+        Path::new(target)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| target.clone())
+    });
+    let mut out_dir = builder
+        .target_dir()
+        .context("Failed to run cargo metadata to find out target directory")?
+        .to_owned();
+    if let Some(target_name) = &target_name {
+        out_dir.push(target_name);
+    }
+    out_dir.push(profile_dir);
+
+    let kernel_packages: Vec<_> = builder
+        .kernel_packages()
+        .context("Failed to run cargo metadata to find kernel packages")?
+        .into_iter()
+        .cloned()
+        .collect();
+    for package in kernel_packages {
+        let mut kernel_config = config::read_config(&package.manifest_path)?.for_profile(&profile);
+        if let Some(out_dir) = args.out_dir() {
+            kernel_config.out_dir = Some(resolve_out_dir(out_dir, &package.manifest_path)?);
+        }
+        for executable in predict_executables(&package, &selection, &out_dir) {
+            let mut kernel_config = kernel_config.clone();
+            let bin = if executable.test {
+                kernel_config = kernel_config.for_test(&executable.target_name);
+                format!("{}-{}", executable.target_kind, executable.target_name)
+            } else {
+                executable.target_name.clone()
+            };
+            let image_name_vars = ImageNameVars {
+                package: package.name.clone(),
+                bin,
+                version: package.version.to_string(),
+                target: target_name.clone().unwrap_or_else(|| "host".into()),
+                profile: profile.clone(),
+            };
+            let executable_dir = executable
+                .path
+                .parent()
+                .ok_or_else(|| anyhow!("executable has no parent path"))?;
+            let bootimage_path = kernel_config.image_path(&image_name_vars, executable_dir);
+            plan.commands.extend(builder.plan_create_bootimage(
+                &package.manifest_path,
+                &executable.path,
+                &bootimage_path,
+                &kernel_config,
+            )?);
+        }
+    }
+
+    plan.print(format);
+    Ok(())
+}
+
+/// A kernel executable that `cargo bootimage --dry-run` expects the build to create.
+#[derive(PartialEq)]
+struct PredictedExecutable {
+    target_name: String,
+    target_kind: String,
+    /// Whether the executable is built with the test harness, like `KernelExecutable::test`
+    test: bool,
+    path: PathBuf,
+}
+
+/// Returns the targets that the given cargo arguments select as `(kind, name)` pairs, where
+/// a name of `None` selects all targets of the kind.
+///
+/// Without selection arguments, cargo builds the bin targets.
+fn selected_targets(cargo_args: &[String]) -> Result<Vec<(&'static str, Option<String>)>> {
+    let mut selection = Vec::new();
+    let mut cargo_args = cargo_args.iter();
+    while let Some(arg) = cargo_args.next() {
+        let (flag, value) = match arg.split_once('=') {
+            Some((flag, value)) => (flag, Some(value.to_owned())),
+            None => (arg.as_str(), None),
+        };
+        let (kind, all) = match flag {
+            "--bins" => ("bin", true),
+            "--bin" => ("bin", false),
+            "--tests" => ("test", true),
+            "--test" => ("test", false),
+            "--examples" => ("example", true),
+            "--example" => ("example", false),
+            "--lib" | "--bench" | "--benches" | "--all-targets" => {
+                return Err(anyhow!(
+                    "`{}` is not supported with `--dry-run`, the created images can't be \
+                     predicted",
+                    flag
+                ))
+            }
+            _ => continue,
+        };
+        let name = if all {
+            None
+        } else {
+            let name = value.or_else(|| cargo_args.next().cloned());
+            Some(name.ok_or_else(|| anyhow!("`{}` requires a target name", flag))?)
+        };
+        selection.push((kind, name));
+    }
+    if selection.is_empty() {
+        selection.push(("bin", None));
+    }
+    Ok(selection)
+}
+
+/// Predicts the kernel executables that building the selected targets of the given package
+/// creates in `out_dir`.
+///
+/// Selecting integration tests builds the test executables of the integration tests and
+/// additionally the bin targets, which cargo builds for them. `--tests` also builds the unit
+/// tests of the bin and lib targets.
+fn predict_executables(
+    package: &cargo_metadata::Package,
+    selection: &[(&str, Option<String>)],
+    out_dir: &Path,
+) -> Vec<PredictedExecutable> {
+    let targets = |kind: &str| -> Vec<&cargo_metadata::Target> {
+        package
+            .targets
+            .iter()
+            .filter(|t| t.kind.iter().any(|k| k == kind))
+            .collect()
+    };
+    let plain = |target: &cargo_metadata::Target, kind: &str| {
+        let path = match kind {
+            "example" => out_dir.join("examples").join(&target.name),
+            _ => out_dir.join(&target.name),
+        };
+        PredictedExecutable {
+            target_name: target.name.clone(),
+            target_kind: kind.to_owned(),
+            test: false,
+            path,
+        }
+    };
+    let test = |target: &cargo_metadata::Target, kind: &str| PredictedExecutable {
+        target_name: target.name.clone(),
+        target_kind: kind.to_owned(),
+        test: true,
+        path: out_dir
+            .join("deps")
+            .join(format!("{}-<hash>", target.name.replace('-', "_"))),
+    };
+
+    let mut executables = Vec::new();
+    for (kind, name) in selection {
+        let selected: Vec<_> = targets(kind)
+            .into_iter()
+            .filter(|t| name.as_ref().is_none_or(|name| *name == t.name))
+            .collect();
+        match *kind {
+            "test" => {
+                executables.extend(selected.iter().map(|t| test(t, "test")));
+                if name.is_none() {
+                    executables.extend(targets("bin").iter().map(|t| test(t, "bin")));
+                    executables.extend(targets("lib").iter().map(|t| test(t, "lib")));
+                }
+                if !selected.is_empty() {
+                    executables.extend(targets("bin").iter().map(|t| plain(t, "bin")));
+                }
+            }
+            kind => executables.extend(selected.iter().map(|t| plain(t, kind))),
+        }
+    }
+    let mut unique = Vec::new();
+    for executable in executables {
+        if !unique.contains(&executable) {
+            unique.push(executable);
+        }
+    }
+    unique
+}
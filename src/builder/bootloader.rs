@@ -1,11 +1,56 @@
-use super::error::BootloaderError;
+use super::{error::BootloaderError, has_bootloader_dependency};
+use crate::config::Config;
 use cargo_metadata::{Metadata, Package};
 use std::{
+    collections::BTreeMap,
+    env,
+    ffi::OsString,
     fs,
     path::{Path, PathBuf},
     process::Command,
 };
 
+/// Environment variables that are passed through to the bootloader build.
+///
+/// All other variables of the caller are removed to ensure that the bootloader build does not
+/// depend on the environment it is started in (e.g. `RUSTFLAGS` or the `CARGO_*` variables
+/// that cargo sets for runners).
+const PASSTHROUGH_ENV: &[&str] = &[
+    "PATH",
+    "HOME",
+    "USER",
+    "TMPDIR",
+    "CARGO_HOME",
+    "RUSTUP_HOME",
+    "RUSTUP_TOOLCHAIN",
+    "HTTP_PROXY",
+    "HTTPS_PROXY",
+    "NO_PROXY",
+];
+
+/// Additional environment variables that are passed through to the bootloader build on
+/// Windows.
+///
+/// Build scripts and linkers need them to find tools (`PATHEXT`, `COMSPEC`) and to create
+/// temporary files (`TEMP`, `TMP`). The names are upper case since the comparison is
+/// case-insensitive on Windows.
+const WINDOWS_PASSTHROUGH_ENV: &[&str] = &[
+    "USERPROFILE",
+    "APPDATA",
+    "LOCALAPPDATA",
+    "SYSTEMROOT",
+    "WINDIR",
+    "PATHEXT",
+    "COMSPEC",
+    "TEMP",
+    "TMP",
+];
+
+/// Prefixes of environment variables that are passed through to the bootloader build.
+///
+/// These only configure how cargo accesses the network and registries, not the build itself.
+const PASSTHROUGH_ENV_PREFIXES: &[&str] = &["CARGO_HTTP_", "CARGO_NET_", "CARGO_REGISTRIES_"];
+
 pub struct BuildConfig {
     manifest_path: PathBuf,
     bootloader_name: String,
@@ -15,14 +60,19 @@ pub struct BuildConfig {
     kernel_bin_path: PathBuf,
     kernel_manifest_path: PathBuf,
     build_std: Option<String>,
+    profile: String,
+    rustflags: Vec<String>,
+    env: BTreeMap<String, String>,
 }
 
 impl BuildConfig {
-    /// Derives the bootloader build config from the project's metadata.
+    /// Derives the bootloader build config from the project's metadata and the kernel's
+    /// bootimage configuration.
     pub fn from_metadata(
         project_metadata: &Metadata,
         kernel_manifest_path: &Path,
         kernel_bin_path: &Path,
+        config: &Config,
     ) -> Result<Self, BootloaderError> {
         let kernel_pkg = project_metadata
             .packages
@@ -85,16 +135,29 @@ impl BuildConfig {
             .ok_or(BootloaderError::CargoMetadataIncomplete {
                 key: format!("resolve[\"{}\"]", bootloader_pkg.name),
             })?;
-        let mut features = bootloader_resolve.features.clone();
+        let mut features = config
+            .bootloader_features
+            .clone()
+            .unwrap_or_else(|| bootloader_resolve.features.clone());
         if binary_feature {
             features.push("binary".into());
         }
+        features.sort();
+        features.dedup();
 
+        // use a separate target directory for each bootloader version and feature set so that
+        // builds for different kernels don't invalidate each other
         let bootloader_name = &bootloader_pkg.name;
+        let feature_set = if features.is_empty() {
+            "no-features".to_owned()
+        } else {
+            features.join("+")
+        };
         let target_dir = project_metadata
             .target_directory
             .join("bootimage")
-            .join(bootloader_name);
+            .join(format!("{}-{}", bootloader_name, bootloader_pkg.version))
+            .join(feature_set);
 
         Ok(BuildConfig {
             manifest_path: bootloader_pkg.manifest_path.clone(),
@@ -105,15 +168,24 @@ impl BuildConfig {
             kernel_manifest_path: kernel_pkg.manifest_path.clone(),
             kernel_bin_path: kernel_bin_path.to_owned(),
             build_std,
+            profile: config.bootloader_profile.clone(),
+            rustflags: config.bootloader_rustflags.clone(),
+            env: config.bootloader_env.clone(),
         })
     }
 
     /// Creates the cargo build command for building the bootloader.
+    ///
+    /// The environment of the command is cleared except for a few variables that are needed to
+    /// run cargo (see `PASSTHROUGH_ENV`), so that the build is the same no matter which
+    /// variables the caller has set.
     pub fn build_command(&self) -> Command {
-        let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned());
+        let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned());
         let mut cmd = Command::new(&cargo);
+        cmd.env_clear();
+        cmd.envs(passthrough_env());
         if let Some(build_std) = &self.build_std {
-            cmd.arg("build").arg(&format!("-Zbuild-std={}", build_std));
+            cmd.arg("build").arg(format!("-Zbuild-std={}", build_std));
         } else {
             cmd.arg("xbuild");
         }
@@ -124,36 +196,98 @@ impl BuildConfig {
         cmd.arg("--features")
             .arg(self.features.as_slice().join(" "));
         cmd.arg("--target").arg(&self.target);
-        cmd.arg("--release");
+        if self.profile == "release" {
+            cmd.arg("--release");
+        } else {
+            cmd.arg("--profile").arg(&self.profile);
+        }
         cmd.env("KERNEL", &self.kernel_bin_path);
         cmd.env("KERNEL_MANIFEST", &self.kernel_manifest_path);
-        cmd.env("RUSTFLAGS", "");
+        // `CARGO_ENCODED_RUSTFLAGS` takes precedence over all other rustflags sources, including
+        // the `rustflags` keys in `.cargo/config` files
+        cmd.env("CARGO_ENCODED_RUSTFLAGS", self.rustflags.join("\x1f"));
+        // for cargo-xbuild, which splits `RUSTFLAGS` at spaces (documented for the config key)
+        cmd.env("RUSTFLAGS", self.rustflags.join(" "));
         cmd.env(
             "XBUILD_SYSROOT_PATH",
             self.target_dir.join("bootloader-sysroot"),
         ); // for cargo-xbuild
+        cmd.envs(&self.env);
         cmd
     }
+
+    /// Returns the path at which the bootloader build places the bootloader executable.
+    ///
+    /// This is only used for describing the build, the actual path is read from the JSON
+    /// output of the build command.
+    pub fn executable_path(&self) -> PathBuf {
+        let target_name = self.target.file_stem().unwrap_or_default();
+        let profile_dir = match self.profile.as_str() {
+            "dev" | "test" => "debug",
+            "bench" => "release",
+            other => other,
+        };
+        self.target_dir
+            .join(target_name)
+            .join(profile_dir)
+            .join(&self.bootloader_name)
+    }
+}
+
+/// Returns the environment variables of the current process that should be passed to the
+/// bootloader build.
+fn passthrough_env() -> impl Iterator<Item = (OsString, OsString)> {
+    env::vars_os().filter(|(key, _)| {
+        // environment variable names are case-insensitive on Windows
+        let key = key.to_string_lossy().to_ascii_uppercase();
+        PASSTHROUGH_ENV.contains(&key.as_str())
+            || (cfg!(windows) && WINDOWS_PASSTHROUGH_ENV.contains(&key.as_str()))
+            || PASSTHROUGH_ENV_PREFIXES
+                .iter()
+                .any(|prefix| key.starts_with(prefix))
+    })
 }
 
-/// Returns the package metadata for the bootloader crate
+/// Returns the package metadata for the bootloader crate of the given kernel
+///
+/// The package is looked up in the dependency graph of the kernel package, since different
+/// packages of a workspace can depend on different bootloader versions.
 fn bootloader_package<'a>(
     project_metadata: &'a Metadata,
     kernel_package: &Package,
 ) -> Result<&'a Package, BootloaderError> {
-    let bootloader_name = {
-        let mut dependencies = kernel_package.dependencies.iter();
-        let bootloader_package = dependencies
-            .find(|p| p.rename.as_ref().unwrap_or(&p.name) == "bootloader")
-            .ok_or(BootloaderError::BootloaderNotFound)?;
-        bootloader_package.name.clone()
-    };
+    if !has_bootloader_dependency(kernel_package) {
+        return Err(BootloaderError::BootloaderNotFound);
+    }
+
+    let resolve_opt = project_metadata.resolve.as_ref();
+    let resolve = resolve_opt.ok_or(BootloaderError::CargoMetadataIncomplete {
+        key: "resolve".into(),
+    })?;
+    let kernel_resolve = resolve
+        .nodes
+        .iter()
+        .find(|n| n.id == kernel_package.id)
+        .ok_or_else(|| BootloaderError::CargoMetadataIncomplete {
+            key: format!("resolve[\"{}\"]", kernel_package.name),
+        })?;
+    let bootloader_id = &kernel_resolve
+        .deps
+        .iter()
+        .find(|d| d.name == "bootloader")
+        .ok_or_else(|| BootloaderError::CargoMetadataIncomplete {
+            key: format!(
+                "resolve[\"{}\"].deps[name = `bootloader`]",
+                kernel_package.name
+            ),
+        })?
+        .pkg;
 
     project_metadata
         .packages
         .iter()
-        .find(|p| p.name == bootloader_name)
-        .ok_or(BootloaderError::CargoMetadataIncomplete {
-            key: format!("packages[name = `{}`", &bootloader_name),
+        .find(|p| &p.id == bootloader_id)
+        .ok_or_else(|| BootloaderError::CargoMetadataIncomplete {
+            key: format!("packages[id = `{}`]", bootloader_id),
         })
 }
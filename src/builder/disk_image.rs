@@ -1,23 +1,556 @@
-use super::error::DiskImageError;
-use std::{path::Path, process::Command};
+use super::{error::DiskImageError, logs};
+use crate::config::{PrebuiltField, PrebuiltFieldValue};
+use std::{
+    convert::TryFrom,
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use xmas_elf::{
+    program,
+    sections::{SectionData, SectionHeader, ShType, SHF_ALLOC},
+    symbol_table::Entry,
+    ElfFile,
+};
+
+const BLOCK_SIZE: u64 = 512;
 
 pub fn create_disk_image(
     bootloader_elf_path: &Path,
     output_bin_path: &Path,
+    log_path: &Path,
 ) -> Result<(), DiskImageError> {
-    let llvm_tools = llvm_tools::LlvmTools::new()?;
-    let objcopy = llvm_tools
-        .tool(&llvm_tools::exe("llvm-objcopy"))
-        .ok_or(DiskImageError::LlvmObjcopyNotFound)?;
+    run_objcopy(
+        objcopy_command(bootloader_elf_path, output_bin_path)?,
+        log_path,
+    )?;
+    pad_to_nearest_block_size(output_bin_path)?;
+    Ok(())
+}
 
-    // convert bootloader to binary
-    let mut cmd = Command::new(objcopy);
+/// Returns the `llvm-objcopy` command that converts the bootloader ELF file to a flat binary.
+pub fn objcopy_command(
+    bootloader_elf_path: &Path,
+    output_bin_path: &Path,
+) -> Result<Command, DiskImageError> {
+    let mut cmd = Command::new(llvm_tool(
+        "llvm-objcopy",
+        DiskImageError::LlvmObjcopyNotFound,
+    )?);
     cmd.arg("-I").arg("elf64-x86-64");
     cmd.arg("-O").arg("binary");
     cmd.arg("--binary-architecture=i386:x86-64");
     cmd.arg(bootloader_elf_path);
     cmd.arg(output_bin_path);
-    let output = cmd.output().map_err(|err| DiskImageError::Io {
+    Ok(cmd)
+}
+
+fn llvm_tool(name: &str, not_found: DiskImageError) -> Result<PathBuf, DiskImageError> {
+    let llvm_tools = llvm_tools::LlvmTools::new()?;
+    llvm_tools.tool(&llvm_tools::exe(name)).ok_or(not_found)
+}
+
+/// Creates a bootable disk image from a prebuilt bootloader and the given kernel executable.
+///
+/// Static archives and relocatable object files are linked with the kernel in the same way as
+/// the build script of the `bootloader` crate does it, so the result is identical to a normal
+/// bootloader build. Linked bootloader ELF files must contain their relocations (i.e. be linked
+/// with `--emit-relocs`): the kernel that they were linked with is replaced and all references
+/// to the `_kernel_start_addr`, `_kernel_end_addr`, and `_kernel_size` symbols are updated, which
+/// gives the same result as linking the bootloader with the new kernel. For flat bootloader
+/// binaries, the kernel is appended at the next block boundary and the given `fields` are set
+/// to its location and size.
+///
+/// In all cases, the debug information is stripped from the kernel before it is added to the
+/// image. The output of all executed commands is appended to the file at `log_path`.
+pub fn create_disk_image_from_prebuilt(
+    prebuilt_path: &Path,
+    linker_script: Option<&Path>,
+    fields: &[PrebuiltField],
+    kernel_bin_path: &Path,
+    output_bin_path: &Path,
+    log_path: &Path,
+) -> Result<(), DiskImageError> {
+    let io_err = |message| move |error| DiskImageError::Io { message, error };
+    let format = prebuilt_format(prebuilt_path)?;
+    if !fields.is_empty() && format != PrebuiltFormat::Flat {
+        return Err(DiskImageError::PrebuiltFieldInvalid {
+            offset: fields[0].offset,
+            message: "fields can only be set for flat bootloader binaries".into(),
+        });
+    }
+    match format {
+        PrebuiltFormat::Linkable => {
+            let commands = link_commands(
+                prebuilt_path,
+                linker_script,
+                kernel_bin_path,
+                output_bin_path,
+            )?;
+            run_objcopy(commands.strip_kernel, log_path)?;
+            run_objcopy(commands.wrap_kernel, log_path)?;
+            let mut link = commands.link;
+            let output = logs::output(&mut link, log_path, false)
+                .map_err(io_err("failed to execute rust-lld command"))?;
+            if !output.status.success() {
+                return Err(DiskImageError::LinkFailed {
+                    stderr: output.stderr,
+                });
+            }
+            create_disk_image(&commands.bootloader_elf_path, output_bin_path, log_path)
+        }
+        PrebuiltFormat::LinkedElf => {
+            let (strip_kernel, stripped_kernel_path) =
+                strip_kernel_command(kernel_bin_path, output_bin_path)?;
+            run_objcopy(strip_kernel, log_path)?;
+            run_objcopy(
+                extract_bootloader_command(prebuilt_path, output_bin_path)?,
+                log_path,
+            )?;
+            let kernel =
+                fs::read(&stripped_kernel_path).map_err(io_err("failed to read kernel"))?;
+            let elf_data =
+                fs::read(prebuilt_path).map_err(io_err("failed to read prebuilt bootloader"))?;
+            let mut image =
+                fs::read(output_bin_path).map_err(io_err("failed to read boot image"))?;
+            patch_linked_elf(&elf_data, &mut image, kernel.len() as u64).map_err(|message| {
+                DiskImageError::PrebuiltBootloaderInvalid {
+                    path: prebuilt_path.to_owned(),
+                    message,
+                }
+            })?;
+            image.extend_from_slice(&kernel);
+            fs::write(output_bin_path, image).map_err(io_err("failed to write boot image"))?;
+            pad_to_nearest_block_size(output_bin_path)
+        }
+        PrebuiltFormat::Flat => {
+            let (strip_kernel, stripped_kernel_path) =
+                strip_kernel_command(kernel_bin_path, output_bin_path)?;
+            run_objcopy(strip_kernel, log_path)?;
+            let kernel =
+                fs::read(&stripped_kernel_path).map_err(io_err("failed to read kernel"))?;
+            let mut image =
+                fs::read(prebuilt_path).map_err(io_err("failed to read prebuilt bootloader"))?;
+            let bootloader_len = image.len() as u64;
+            image.resize(align_up(bootloader_len, BLOCK_SIZE) as usize, 0);
+            let kernel_offset = image.len() as u64;
+            for field in fields {
+                write_field(
+                    &mut image,
+                    field,
+                    bootloader_len,
+                    kernel_offset,
+                    kernel.len() as u64,
+                )?;
+            }
+            image.extend_from_slice(&kernel);
+            fs::write(output_bin_path, image).map_err(io_err("failed to write boot image"))?;
+            pad_to_nearest_block_size(output_bin_path)
+        }
+    }
+}
+
+/// Returns the commands that [`create_disk_image_from_prebuilt`] runs for the given prebuilt
+/// bootloader, together with a short description of each command.
+pub fn prebuilt_commands(
+    prebuilt_path: &Path,
+    linker_script: Option<&Path>,
+    kernel_bin_path: &Path,
+    output_bin_path: &Path,
+) -> Result<Vec<(&'static str, Command)>, DiskImageError> {
+    Ok(match prebuilt_format(prebuilt_path)? {
+        PrebuiltFormat::Linkable => {
+            let commands = link_commands(
+                prebuilt_path,
+                linker_script,
+                kernel_bin_path,
+                output_bin_path,
+            )?;
+            let objcopy = objcopy_command(&commands.bootloader_elf_path, output_bin_path)?;
+            vec![
+                ("strip kernel", commands.strip_kernel),
+                ("wrap kernel", commands.wrap_kernel),
+                ("link prebuilt bootloader", commands.link),
+                ("disk image", objcopy),
+            ]
+        }
+        PrebuiltFormat::LinkedElf => vec![
+            (
+                "strip kernel",
+                strip_kernel_command(kernel_bin_path, output_bin_path)?.0,
+            ),
+            (
+                "extract prebuilt bootloader",
+                extract_bootloader_command(prebuilt_path, output_bin_path)?,
+            ),
+        ],
+        PrebuiltFormat::Flat => vec![(
+            "strip kernel",
+            strip_kernel_command(kernel_bin_path, output_bin_path)?.0,
+        )],
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PrebuiltFormat {
+    /// A static archive or relocatable ELF object file
+    Linkable,
+    /// An ELF file that is already linked
+    LinkedElf,
+    /// A flat binary
+    Flat,
+}
+
+fn prebuilt_format(prebuilt_path: &Path) -> Result<PrebuiltFormat, DiskImageError> {
+    const ELF_TYPE_RELOCATABLE: u16 = 1;
+
+    let mut header = Vec::new();
+    fs::File::open(prebuilt_path)
+        .and_then(|file| file.take(18).read_to_end(&mut header))
+        .map_err(|err| DiskImageError::Io {
+            message: "failed to read prebuilt bootloader",
+            error: err,
+        })?;
+    Ok(if header.starts_with(b"!<arch>\n") {
+        PrebuiltFormat::Linkable
+    } else if header.starts_with(b"\x7fELF") && header.len() == 18 {
+        let elf_type = u16::from_le_bytes([header[16], header[17]]);
+        if elf_type == ELF_TYPE_RELOCATABLE {
+            PrebuiltFormat::Linkable
+        } else {
+            PrebuiltFormat::LinkedElf
+        }
+    } else {
+        PrebuiltFormat::Flat
+    })
+}
+
+/// Returns the directory and the file stem for intermediate files next to the disk image.
+fn intermediate_file_base(output_bin_path: &Path) -> Result<(&Path, String), DiskImageError> {
+    let invalid_path = || DiskImageError::InvalidImagePath {
+        path: output_bin_path.to_owned(),
+    };
+    let out_dir = output_bin_path.parent().ok_or_else(invalid_path)?;
+    let file_stem = output_bin_path.file_stem().ok_or_else(invalid_path)?;
+    Ok((out_dir, file_stem.to_string_lossy().into_owned()))
+}
+
+/// Returns the command that strips the debug information from the kernel, in the same way
+/// as the build script of the `bootloader` crate does it, and the path of the stripped kernel.
+fn strip_kernel_command(
+    kernel_bin_path: &Path,
+    output_bin_path: &Path,
+) -> Result<(Command, PathBuf), DiskImageError> {
+    let objcopy = llvm_tool("llvm-objcopy", DiskImageError::LlvmObjcopyNotFound)?;
+    let (out_dir, file_stem) = intermediate_file_base(output_bin_path)?;
+    let stripped_kernel_path = out_dir.join(format!("{}-kernel_stripped", file_stem));
+
+    let mut strip_kernel = Command::new(objcopy);
+    strip_kernel.arg("--strip-debug");
+    strip_kernel.arg(kernel_bin_path);
+    strip_kernel.arg(&stripped_kernel_path);
+    Ok((strip_kernel, stripped_kernel_path))
+}
+
+/// Returns the command that converts a linked bootloader ELF file to a flat binary.
+///
+/// The kernel that the bootloader was linked with is removed from the binary afterwards,
+/// since `llvm-objcopy` refuses to remove sections that relocations refer to.
+fn extract_bootloader_command(
+    prebuilt_path: &Path,
+    output_bin_path: &Path,
+) -> Result<Command, DiskImageError> {
+    objcopy_command(prebuilt_path, output_bin_path)
+}
+
+/// The commands for linking a prebuilt bootloader archive with the kernel.
+pub struct LinkCommands {
+    /// Strips the debug symbols from the kernel to reduce the image size.
+    pub strip_kernel: Command,
+    /// Wraps the stripped kernel in an object file.
+    pub wrap_kernel: Command,
+    /// Links the bootloader archive with the kernel object file.
+    pub link: Command,
+    /// The path of the linked bootloader ELF file.
+    pub bootloader_elf_path: PathBuf,
+}
+
+/// Returns the commands for linking the given bootloader archive with the kernel, mirroring
+/// the build script of the `bootloader` crate.
+///
+/// The intermediate files are placed next to the `output_bin_path`.
+pub fn link_commands(
+    prebuilt_path: &Path,
+    linker_script: Option<&Path>,
+    kernel_bin_path: &Path,
+    output_bin_path: &Path,
+) -> Result<LinkCommands, DiskImageError> {
+    let objcopy = llvm_tool("llvm-objcopy", DiskImageError::LlvmObjcopyNotFound)?;
+    let rust_lld = llvm_tool("rust-lld", DiskImageError::RustLldNotFound)?;
+
+    let linker_script = linker_script
+        .map(PathBuf::from)
+        .unwrap_or_else(|| prebuilt_path.with_file_name("linker.ld"));
+    let bootloader_elf_path = output_bin_path.with_extension("elf");
+    let (out_dir, file_stem) = intermediate_file_base(output_bin_path)?;
+    let (strip_kernel, _) = strip_kernel_command(kernel_bin_path, output_bin_path)?;
+    let stripped_kernel_file_name = format!("{}-kernel_stripped", file_stem);
+    let kernel_object_file_name = format!("{}-kernel_bin.o", file_stem);
+
+    // the symbol names are derived from the input file name
+    let symbol_name: String = stripped_kernel_file_name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let mut wrap_kernel = Command::new(&objcopy);
+    wrap_kernel.arg("-I").arg("binary");
+    wrap_kernel.arg("-O").arg("elf64-x86-64");
+    wrap_kernel.arg("--binary-architecture=i386:x86-64");
+    wrap_kernel.arg("--rename-section").arg(".data=.kernel");
+    wrap_kernel.arg("--redefine-sym");
+    wrap_kernel.arg(format!("_binary_{}_start=_kernel_start_addr", symbol_name));
+    wrap_kernel.arg("--redefine-sym");
+    wrap_kernel.arg(format!("_binary_{}_end=_kernel_end_addr", symbol_name));
+    wrap_kernel.arg("--redefine-sym");
+    wrap_kernel.arg(format!("_binary_{}_size=_kernel_size", symbol_name));
+    wrap_kernel.current_dir(out_dir);
+    wrap_kernel.arg(&stripped_kernel_file_name);
+    wrap_kernel.arg(&kernel_object_file_name);
+
+    let mut link = Command::new(rust_lld);
+    link.arg("-flavor").arg("gnu");
+    link.arg(format!("--script={}", linker_script.display()));
+    link.arg("--gc-sections");
+    link.arg("--whole-archive")
+        .arg(prebuilt_path)
+        .arg("--no-whole-archive");
+    link.arg(out_dir.join(&kernel_object_file_name));
+    link.arg("-o").arg(&bootloader_elf_path);
+
+    Ok(LinkCommands {
+        strip_kernel,
+        wrap_kernel,
+        link,
+        bootloader_elf_path,
+    })
+}
+
+/// Updates all references to the kernel symbols in the flat `image` of a linked bootloader
+/// ELF file for a kernel of the given size, and truncates the image at the kernel start.
+///
+/// The kernel that the ELF file was linked with is placed after the bootloader code, so the
+/// code doesn't move when the kernel changes. Only the values of `_kernel_end_addr` and
+/// `_kernel_size` change, and every use of them is recorded as a relocation if the file was
+/// linked with `--emit-relocs`. The resolved value of each relocation is adjusted by the
+/// difference between the old and the new symbol value, which is exactly what relinking
+/// would produce.
+fn patch_linked_elf(elf_data: &[u8], image: &mut Vec<u8>, kernel_size: u64) -> Result<(), String> {
+    let elf = ElfFile::new(elf_data)?;
+    let symbols = match elf
+        .find_section_by_name(".symtab")
+        .ok_or("the ELF file has no symbol table")?
+        .get_data(&elf)?
+    {
+        SectionData::SymbolTable64(symbols) => symbols,
+        _ => return Err("the ELF file has no 64-bit symbol table".into()),
+    };
+    let symbol_index = |name: &str| {
+        symbols
+            .iter()
+            .position(|symbol| symbol.get_name(&elf) == Ok(name))
+            .ok_or_else(|| format!("the ELF file has no `{}` symbol", name))
+    };
+    let start_index = symbol_index("_kernel_start_addr")?;
+    let end_index = symbol_index("_kernel_end_addr")?;
+    let size_index = symbol_index("_kernel_size")?;
+    let kernel_start = symbols[start_index].value();
+    let deltas = [
+        (
+            end_index,
+            kernel_start + kernel_size,
+            symbols[end_index].value(),
+        ),
+        (size_index, kernel_size, symbols[size_index].value()),
+    ];
+
+    // `llvm-objcopy -O binary` places the sections at their load address, starting with the
+    // lowest one
+    let load_address = |section: &SectionHeader| {
+        elf.program_iter()
+            .filter(|segment| segment.get_type() == Ok(program::Type::Load))
+            .find(|segment| {
+                segment.offset() <= section.offset()
+                    && section.offset() < segment.offset() + segment.file_size()
+            })
+            .map(|segment| segment.physical_addr() + (section.offset() - segment.offset()))
+    };
+    let loaded = |section: &SectionHeader| {
+        section.flags() & SHF_ALLOC != 0
+            && section.size() > 0
+            && section.get_type() != Ok(ShType::NoBits)
+    };
+    let bootloader_sections = || {
+        elf.section_iter()
+            .filter(|section| loaded(section) && section.get_name(&elf) != Ok(".kernel"))
+            .filter_map(|section| Some((load_address(&section)?, section.size())))
+    };
+    let base = bootloader_sections()
+        .map(|(address, _)| address)
+        .min()
+        .ok_or("the ELF file has no loadable sections")?;
+    let kernel_section = elf
+        .find_section_by_name(".kernel")
+        .ok_or("the ELF file has no `.kernel` section")?;
+    if kernel_section.address() != kernel_start {
+        return Err("`_kernel_start_addr` is not the start of the `.kernel` section".into());
+    }
+    let kernel_address = load_address(&kernel_section)
+        .filter(|&address| address >= base)
+        .ok_or("the `.kernel` section is not loaded after the bootloader")?;
+    if bootloader_sections().any(|(address, size)| address + size > kernel_address) {
+        return Err("the bootloader has sections after the `.kernel` section".into());
+    }
+    // remove the kernel that the bootloader was linked with
+    image.resize((kernel_address - base) as usize, 0);
+
+    let mut relocation_count = 0;
+    for section in elf.section_iter() {
+        if section.get_type() != Ok(ShType::Rela) {
+            continue;
+        }
+        let target = elf.section_header(section.info() as u16)?;
+        if !loaded(&target) {
+            // e.g. relocations of debug information
+            continue;
+        }
+        let target_offset = load_address(&target)
+            .and_then(|address| address.checked_sub(base))
+            .ok_or("a relocated section is not loaded")?;
+        let relocations = match section.get_data(&elf)? {
+            SectionData::Rela64(relocations) => relocations,
+            _ => return Err("the ELF file has no 64-bit relocations".into()),
+        };
+        relocation_count += relocations.len();
+        for relocation in relocations {
+            let symbol = relocation.get_symbol_table_index() as usize;
+            let (new, old) = match deltas.iter().find(|(index, _, _)| *index == symbol) {
+                Some((_, new, old)) => (*new, *old),
+                None => continue,
+            };
+            let offset = target_offset + (relocation.get_offset() - target.address());
+            patch_relocation(image, relocation.get_type(), offset, new, old)?;
+        }
+    }
+    if relocation_count == 0 {
+        return Err(
+            "the ELF file contains no relocations, link it with `--emit-relocs` \
+                    (e.g. through `bootloader-rustflags = [\"-C\", \"link-arg=--emit-relocs\"]`)"
+                .into(),
+        );
+    }
+    Ok(())
+}
+
+/// Adds the difference between the `new` and `old` symbol value to the relocated field at
+/// `offset`.
+fn patch_relocation(
+    image: &mut [u8],
+    relocation_type: u32,
+    offset: u64,
+    new: u64,
+    old: u64,
+) -> Result<(), String> {
+    // (width, signed) of the supported x86_64 relocation types
+    let (width, signed) = match relocation_type {
+        1 => (8, false),  // R_X86_64_64
+        2 => (4, true),   // R_X86_64_PC32
+        10 => (4, false), // R_X86_64_32
+        11 => (4, true),  // R_X86_64_32S
+        12 => (2, false), // R_X86_64_16
+        13 => (2, true),  // R_X86_64_PC16
+        14 => (1, false), // R_X86_64_8
+        15 => (1, true),  // R_X86_64_PC8
+        24 => (8, true),  // R_X86_64_PC64
+        other => {
+            return Err(format!(
+                "unsupported relocation type {} for a kernel symbol",
+                other
+            ))
+        }
+    };
+    let field = field_bytes(image, offset, width)
+        .ok_or("a relocation of a kernel symbol is outside of the bootloader")?;
+    let value = read_le(field, signed) + (i128::from(new) - i128::from(old));
+    write_le(field, value, signed)
+        .ok_or_else(|| "the kernel is too large for a relocated field".to_owned())
+}
+
+/// Sets the given field of a flat bootloader binary.
+fn write_field(
+    image: &mut [u8],
+    field: &PrebuiltField,
+    bootloader_len: u64,
+    kernel_offset: u64,
+    kernel_size: u64,
+) -> Result<(), DiskImageError> {
+    let invalid = |message: &str| DiskImageError::PrebuiltFieldInvalid {
+        offset: field.offset,
+        message: message.into(),
+    };
+    let value = match field.value {
+        PrebuiltFieldValue::KernelOffset => kernel_offset,
+        PrebuiltFieldValue::KernelEnd => kernel_offset + kernel_size,
+        PrebuiltFieldValue::KernelSize => kernel_size,
+        PrebuiltFieldValue::KernelBlock => kernel_offset / BLOCK_SIZE,
+        PrebuiltFieldValue::KernelBlocks => align_up(kernel_size, BLOCK_SIZE) / BLOCK_SIZE,
+    };
+    let value = i128::from(value) + i128::from(field.add);
+    let image = &mut image[..bootloader_len as usize];
+    let bytes = field_bytes(image, field.offset, usize::from(field.width))
+        .ok_or_else(|| invalid("the field is outside of the bootloader binary"))?;
+    write_le(bytes, value, value < 0).ok_or_else(|| invalid("the value doesn't fit the field"))
+}
+
+fn field_bytes(image: &mut [u8], offset: u64, width: usize) -> Option<&mut [u8]> {
+    let start = usize::try_from(offset).ok()?;
+    image.get_mut(start..start.checked_add(width)?)
+}
+
+fn read_le(bytes: &[u8], signed: bool) -> i128 {
+    let mut buf = [0; 16];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    let value = i128::from_le_bytes(buf);
+    let bits = bytes.len() as u32 * 8;
+    if signed && value >> (bits - 1) & 1 == 1 {
+        value - (1 << bits)
+    } else {
+        value
+    }
+}
+
+/// Writes the value in little endian byte order, returns `None` if it doesn't fit.
+fn write_le(bytes: &mut [u8], value: i128, signed: bool) -> Option<()> {
+    let bits = bytes.len() as u32 * 8;
+    let (min, max) = if signed {
+        (-(1 << (bits - 1)), (1 << (bits - 1)) - 1)
+    } else {
+        (0, (1 << bits) - 1)
+    };
+    if value < min || value > max {
+        return None;
+    }
+    let len = bytes.len();
+    bytes.copy_from_slice(&value.to_le_bytes()[..len]);
+    Some(())
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    value.div_ceil(align) * align
+}
+
+fn run_objcopy(mut cmd: Command, log_path: &Path) -> Result<(), DiskImageError> {
+    let output = logs::output(&mut cmd, log_path, false).map_err(|err| DiskImageError::Io {
         message: "failed to execute llvm-objcopy command",
         error: err,
     })?;
@@ -26,17 +559,14 @@ pub fn create_disk_image(
             stderr: output.stderr,
         });
     }
-
-    pad_to_nearest_block_size(output_bin_path)?;
     Ok(())
 }
 
 fn pad_to_nearest_block_size(output_bin_path: &Path) -> Result<(), DiskImageError> {
-    const BLOCK_SIZE: u64 = 512;
     use std::fs::OpenOptions;
     let file = OpenOptions::new()
         .write(true)
-        .open(&output_bin_path)
+        .open(output_bin_path)
         .map_err(|err| DiskImageError::Io {
             message: "failed to open boot image",
             error: err,
@@ -30,18 +30,32 @@ pub enum BuildKernelError {
     XbuildNotFound,
 
     /// Running `cargo build` failed.
-    #[error("Kernel build failed.\nStderr: {}", String::from_utf8_lossy(.stderr))]
+    #[error("Kernel build failed. See `{}` for the full output.", .log_path.display())]
     BuildFailed {
-        /// The standard error output.
-        stderr: Vec<u8>,
+        /// The path to the log file that contains the output of the build.
+        log_path: PathBuf,
     },
 
+    /// Failed to create the log directory
+    #[error("{0}")]
+    LogDir(#[from] LogDirError),
+
+    /// The build was interrupted by SIGINT or SIGTERM
+    #[error("Kernel build was interrupted")]
+    Interrupted,
+
     /// The output of `cargo build --message-format=json` was not valid UTF-8
     #[error("Output of kernel build with --message-format=json is not valid UTF-8:\n{0}")]
     BuildJsonOutputInvalidUtf8(std::string::FromUtf8Error),
     /// The output of `cargo build --message-format=json` was not valid JSON
     #[error("Output of kernel build with --message-format=json is not valid JSON:\n{0}")]
     BuildJsonOutputInvalidJson(json::Error),
+    /// The output of `cargo build --message-format=json` did not contain a required key
+    #[error("Output of kernel build with --message-format=json has no `{key}` key")]
+    BuildJsonOutputIncomplete {
+        /// The required key that was not found
+        key: String,
+    },
 }
 
 /// Represents an error that occurred when creating a bootimage.
@@ -57,12 +71,20 @@ pub enum CreateBootimageError {
     CargoMetadata(#[from] cargo_metadata::Error),
 
     /// Building the bootloader failed
-    #[error("Bootloader build failed.\nStderr: {}", String::from_utf8_lossy(.stderr))]
+    #[error("Bootloader build failed. See `{}` for the full output.", .log_path.display())]
     BootloaderBuildFailed {
-        /// The `cargo build` output to standard error
-        stderr: Vec<u8>,
+        /// The path to the log file that contains the output of the build.
+        log_path: PathBuf,
     },
 
+    /// Failed to create the log directory
+    #[error("{0}")]
+    LogDir(#[from] LogDirError),
+
+    /// The bootimage creation was interrupted by SIGINT or SIGTERM
+    #[error("Bootimage creation was interrupted")]
+    Interrupted,
+
     /// Disk image creation failed
     #[error("An error occured while trying to create the disk image: {0}")]
     DiskImage(#[from] DiskImageError),
@@ -84,6 +106,18 @@ pub enum CreateBootimageError {
     BuildJsonOutputInvalidJson(json::Error),
 }
 
+/// Creating the log directory for the build output failed.
+#[derive(Debug, Error)]
+pub enum LogDirError {
+    /// Error while running `cargo metadata` to find out the target directory
+    #[error("Error while running `cargo metadata` for current project: {0:?}")]
+    CargoMetadata(#[from] cargo_metadata::Error),
+
+    /// Failed to create the log directory
+    #[error("Failed to create log directory: {0}")]
+    Io(#[from] io::Error),
+}
+
 /// There is something wrong with the bootloader dependency.
 #[derive(Debug, Error)]
 pub enum BootloaderError {
@@ -140,6 +174,42 @@ pub enum DiskImageError {
         stderr: Vec<u8>,
     },
 
+    /// The llvm-tools component did not contain the `rust-lld` executable
+    #[error("Could not find `rust-lld` in the `llvm-tools-preview` rustup component.")]
+    RustLldNotFound,
+
+    /// Linking the prebuilt bootloader with the kernel failed
+    #[error("Failed to link the prebuilt bootloader: {}", String::from_utf8_lossy(.stderr))]
+    LinkFailed {
+        /// The output of `rust-lld` to standard error
+        stderr: Vec<u8>,
+    },
+
+    /// The kernel can't be patched into the linked prebuilt bootloader ELF file
+    #[error("Invalid prebuilt bootloader `{}`: {message}", .path.display())]
+    PrebuiltBootloaderInvalid {
+        /// The path to the prebuilt bootloader
+        path: PathBuf,
+        /// Describes the problem
+        message: String,
+    },
+
+    /// A `bootloader-prebuilt-fields` entry can't be written
+    #[error("Invalid `bootloader-prebuilt-fields` entry at offset {offset:#x}: {message}")]
+    PrebuiltFieldInvalid {
+        /// The offset of the field in the bootloader binary
+        offset: u64,
+        /// Describes the problem
+        message: String,
+    },
+
+    /// The disk image path has no parent directory or no file name
+    #[error("Invalid disk image path `{}`", .path.display())]
+    InvalidImagePath {
+        /// The path of the disk image
+        path: PathBuf,
+    },
+
     /// An unexpected I/O error occurred
     #[error("I/O error: {message}:\n{error}")]
     Io {
@@ -0,0 +1,183 @@
+use crate::interrupt;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+    sync::Mutex,
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The length of the `YYYYMMDD-HHMMSS` timestamp of log directory names.
+const TIMESTAMP_LEN: usize = 15;
+
+/// Creates a new log directory `target_dir/bootimage/logs/<timestamp>-<name>`.
+///
+/// The timestamp is the current UTC time. If the directory already exists, e.g. because
+/// of a parallel invocation, a numeric suffix is appended. The oldest log directories are
+/// removed so that at most `retention` directories are kept, unless `retention` is 0.
+pub fn create_log_dir(target_dir: &Path, name: &str, retention: u32) -> io::Result<PathBuf> {
+    let logs_dir = target_dir.join("bootimage").join("logs");
+    fs::create_dir_all(&logs_dir)?;
+    if retention > 0 {
+        prune_log_dirs(&logs_dir, retention as usize - 1)?;
+    }
+    let base_name = format!("{}-{}", timestamp(), name);
+    let mut path = logs_dir.join(&base_name);
+    let mut suffix = 1;
+    loop {
+        match fs::create_dir(&path) {
+            Ok(()) => return Ok(path),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                suffix += 1;
+                path = logs_dir.join(format!("{}-{}", base_name, suffix));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Removes the oldest log directories in `logs_dir`, so that at most `keep` are left.
+///
+/// Only directories whose name starts with a timestamp are considered. Since the timestamp
+/// has a fixed width, the names sort chronologically; directories of the same second are
+/// ordered by their modification time. Directories that can't be removed, e.g. because
+/// another process still writes to them, are skipped.
+fn prune_log_dirs(logs_dir: &Path, keep: usize) -> io::Result<()> {
+    let mut log_dirs = Vec::new();
+    for entry in fs::read_dir(logs_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_dir() && is_timestamped(&name) {
+            let modified = entry.metadata().and_then(|m| m.modified()).ok();
+            log_dirs.push((name[..TIMESTAMP_LEN].to_owned(), modified, entry.path()));
+        }
+    }
+    if log_dirs.len() <= keep {
+        return Ok(());
+    }
+    log_dirs.sort();
+    for (_, _, path) in &log_dirs[..log_dirs.len() - keep] {
+        let _ = fs::remove_dir_all(path);
+    }
+    Ok(())
+}
+
+/// Returns whether the given directory name starts with a `YYYYMMDD-HHMMSS-` timestamp.
+fn is_timestamped(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    bytes.len() > TIMESTAMP_LEN + 1
+        && bytes[..8].iter().all(u8::is_ascii_digit)
+        && bytes[8] == b'-'
+        && bytes[9..TIMESTAMP_LEN].iter().all(u8::is_ascii_digit)
+        && bytes[TIMESTAMP_LEN] == b'-'
+}
+
+/// Runs the given command and appends its stdout and stderr to the file at `log_path`.
+///
+/// If `tee` is set, the output is additionally forwarded to the stdout and stderr of the
+/// current process while the command is running. Like `Command::output`, this returns the
+/// captured output of the command.
+///
+/// The command is started in a new process group, which is terminated on interrupt.
+pub fn output(cmd: &mut Command, log_path: &Path, tee: bool) -> io::Result<Output> {
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path)?;
+    writeln!(log, "$ {:?}\n", cmd)?;
+    let log = Mutex::new(log);
+
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut child = interrupt::spawn(cmd, true)?;
+    let stdout = child.stdout.take().expect("child stdout is piped");
+    let stderr = child.stderr.take().expect("child stderr is piped");
+
+    let (stdout, stderr) = thread::scope(|scope| {
+        let log = &log;
+        let stdout = scope.spawn(move || {
+            let terminal = if tee { Some(io::stdout()) } else { None };
+            copy_stream(stdout, log, terminal)
+        });
+        let stderr = scope.spawn(move || {
+            let terminal = if tee { Some(io::stderr()) } else { None };
+            copy_stream(stderr, log, terminal)
+        });
+        (
+            stdout.join().expect("stdout thread panicked"),
+            stderr.join().expect("stderr thread panicked"),
+        )
+    });
+    let status = child.wait()?;
+
+    Ok(Output {
+        status,
+        stdout: stdout?,
+        stderr: stderr?,
+    })
+}
+
+/// Copies `source` to the log file and the optional `terminal` until it is closed.
+///
+/// Returns all data that was read from `source`.
+fn copy_stream(
+    mut source: impl Read,
+    log: &Mutex<File>,
+    mut terminal: Option<impl Write>,
+) -> io::Result<Vec<u8>> {
+    let mut captured = Vec::new();
+    let mut buf = [0; 8192];
+    loop {
+        let len = match source.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        let data = &buf[..len];
+        log.lock()
+            .expect("log file mutex poisoned")
+            .write_all(data)?;
+        if let Some(terminal) = &mut terminal {
+            terminal.write_all(data)?;
+            terminal.flush()?;
+        }
+        captured.extend_from_slice(data);
+    }
+    Ok(captured)
+}
+
+/// Returns the current UTC time in the format `YYYYMMDD-HHMMSS`.
+fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // convert the days since the epoch to a civil date (see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
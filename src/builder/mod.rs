@@ -1,8 +1,8 @@
 //! Provides functions to build the kernel and the bootloader.
 
-use crate::config::Config;
+use crate::{config::Config, interrupt, plan::CommandDescription};
 use cargo_metadata::Metadata;
-use error::{BootloaderError, BuildKernelError, BuilderError, CreateBootimageError};
+use error::{BootloaderError, BuildKernelError, BuilderError, CreateBootimageError, LogDirError};
 use std::{
     path::{Path, PathBuf},
     process,
@@ -14,11 +14,143 @@ mod bootloader;
 mod disk_image;
 /// Contains the errors types returned by the `Builder` methods.
 pub mod error;
+/// Provides functions to write the output of the build commands to log files.
+mod logs;
+
+/// An executable that was built by [`Builder::build_kernel`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct KernelExecutable {
+    /// The path to the built executable.
+    pub path: PathBuf,
+    /// The cargo package id of the package that the executable belongs to.
+    pub package_id: String,
+    /// The name of the cargo target, e.g. the name of the binary or integration test.
+    pub target_name: String,
+    /// The kind of the cargo target, e.g. `bin`, `test`, `bench`, or `example`.
+    pub target_kind: String,
+    /// Whether the executable was built with the test harness (i.e. it is a test or bench).
+    pub test: bool,
+}
+
+impl KernelExecutable {
+    /// Returns the name that is used for the `{bin}` placeholder of the disk image name.
+    ///
+    /// The name is based on the target name instead of the file stem of the executable
+    /// because test executables have a hash suffix. Test executables are additionally
+    /// prefixed with their target kind to avoid collisions between e.g. the unit tests of
+    /// a binary and an integration test with the same name.
+    pub fn image_bin_name(&self) -> String {
+        if self.test {
+            format!("{}-{}", self.target_kind, self.target_name)
+        } else {
+            self.target_name.clone()
+        }
+    }
+}
+
+/// The output directories of the built-in cargo profiles.
+///
+/// All other profiles use a directory with the same name. For executables in one of these
+/// directories, the first profile of the directory is assumed.
+const PROFILE_DIR_NAMES: &[(&str, &str)] = &[
+    ("dev", "debug"),
+    ("test", "debug"),
+    ("release", "release"),
+    ("bench", "release"),
+];
+
+/// Returns the name of the output directory of the given cargo profile, e.g. `debug` for
+/// `dev`.
+pub fn profile_dir_name(profile: &str) -> &str {
+    PROFILE_DIR_NAMES
+        .iter()
+        .find(|(name, _)| *name == profile)
+        .map_or(profile, |(_, dir)| dir)
+}
+
+/// Returns the profile directory (e.g. `target/debug`) of the given executable.
+fn profile_dir(executable: &Path) -> Option<&Path> {
+    let dir = executable.parent()?;
+    if dir.ends_with("deps") || dir.ends_with("examples") {
+        dir.parent()
+    } else {
+        Some(dir)
+    }
+}
+
+/// Returns whether the given package has a dependency named `bootloader`.
+///
+/// The dependency can also be a renamed crate.
+pub fn has_bootloader_dependency(package: &cargo_metadata::Package) -> bool {
+    package
+        .dependencies
+        .iter()
+        .any(|d| d.rename.as_ref().unwrap_or(&d.name) == "bootloader")
+}
+
+/// Creates the `cargo build` command for the kernel, without the `--message-format` argument.
+fn kernel_build_command(args: &[String], config: &Config) -> process::Command {
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned());
+    let mut cmd = process::Command::new(cargo);
+    cmd.args(&config.build_command);
+    cmd.args(args);
+    cmd
+}
+
+/// Guesses the cargo profile that was used to build the given executable from its output
+/// directory.
+///
+/// Returns `None` if the executable is not placed in a profile directory, e.g. for doctests.
+pub fn profile_from_executable_path(executable: &Path) -> Option<String> {
+    let dir_name = profile_dir(executable)?.file_name()?.to_str()?;
+    if dir_name.starts_with("rustdoctest") {
+        return None;
+    }
+    let profile = PROFILE_DIR_NAMES
+        .iter()
+        .find(|(_, dir)| *dir == dir_name)
+        .map_or(dir_name, |(profile, _)| profile);
+    Some(profile.into())
+}
+
+/// Returns the name of the test target that the given test executable was built from.
+///
+/// This is the file stem of the executable without the hash suffix that cargo appends. Note
+/// that cargo replaces dashes in target names with underscores, so the executable of
+/// `tests/should-panic.rs` results in `should_panic`.
+pub fn test_name_from_executable_path(executable: &Path) -> Option<String> {
+    let file_stem = executable.file_stem()?.to_str()?;
+    match file_stem.rsplit_once('-') {
+        Some((name, hash)) if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+            Some(name.into())
+        }
+        _ => Some(file_stem.into()),
+    }
+}
+
+/// Guesses the compilation target of the given executable from its output directory.
+///
+/// Returns `host` for executables that were built without a `--target` argument and `None`
+/// if the executable is not placed in the given target directory.
+pub fn target_from_executable_path(executable: &Path, target_dir: &Path) -> Option<String> {
+    let target_subdir = profile_dir(executable)?.parent()?;
+    if target_subdir == target_dir {
+        Some("host".into())
+    } else if target_subdir.parent()? == target_dir {
+        Some(target_subdir.file_name()?.to_str()?.into())
+    } else {
+        None
+    }
+}
 
 /// Allows building the kernel and creating a bootable disk image with it.
 pub struct Builder {
     manifest_path: PathBuf,
     project_metadata: Option<Metadata>,
+    log_name: Option<String>,
+    log_dir: Option<PathBuf>,
+    log_retention: u32,
 }
 
 impl Builder {
@@ -41,6 +173,9 @@ impl Builder {
         Ok(Builder {
             manifest_path,
             project_metadata: None,
+            log_name: None,
+            log_dir: None,
+            log_retention: crate::config::DEFAULT_LOG_RETENTION,
         })
     }
 
@@ -49,10 +184,35 @@ impl Builder {
         &self.manifest_path
     }
 
+    /// Sets the name that is appended to the timestamp of the log directory.
+    ///
+    /// Defaults to the name of the directory that contains the Cargo.toml. If a log directory
+    /// was already created, the following commands are logged to a new directory with the
+    /// given name.
+    pub fn set_log_name(&mut self, name: &str) {
+        self.log_name = Some(name.to_owned());
+        self.log_dir = None;
+    }
+
+    /// Sets how many log directories are kept in `target/bootimage/logs` (see
+    /// [`Config::log_retention`]).
+    pub fn set_log_retention(&mut self, retention: u32) {
+        self.log_retention = retention;
+    }
+
+    /// Returns the directory that the output of the build commands is written to.
+    ///
+    /// The directory is `target/bootimage/logs/<timestamp>-<name>`. It is created when the
+    /// first command is executed, so `None` is returned before that.
+    pub fn log_dir(&self) -> Option<&Path> {
+        self.log_dir.as_deref()
+    }
+
     /// Builds the kernel by executing `cargo build` with the given arguments.
     ///
-    /// Returns a list of paths to all built executables. For crates with only a single binary,
-    /// the returned list contains only a single element.
+    /// Returns a list of all built executables. For crates with only a single binary,
+    /// the returned list contains only a single element. If test, bench, or example targets
+    /// are selected through the arguments (e.g. `--tests`), they are returned too.
     ///
     /// If the quiet argument is set to true, all output to stdout is suppressed.
     pub fn build_kernel(
@@ -60,24 +220,32 @@ impl Builder {
         args: &[String],
         config: &Config,
         quiet: bool,
-    ) -> Result<Vec<PathBuf>, BuildKernelError> {
+    ) -> Result<Vec<KernelExecutable>, BuildKernelError> {
+        let result = self.build_kernel_inner(args, config, quiet);
+        if interrupt::interrupted() {
+            return Err(BuildKernelError::Interrupted);
+        }
+        result
+    }
+
+    fn build_kernel_inner(
+        &mut self,
+        args: &[String],
+        config: &Config,
+        quiet: bool,
+    ) -> Result<Vec<KernelExecutable>, BuildKernelError> {
         if !quiet {
             println!("Building kernel");
         }
 
         // try to build kernel
-        let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned());
-        let mut cmd = process::Command::new(&cargo);
-        cmd.args(&config.build_command);
-        cmd.args(args);
-        if !quiet {
-            cmd.stdout(process::Stdio::inherit());
-            cmd.stderr(process::Stdio::inherit());
-        }
-        let output = cmd.output().map_err(|err| BuildKernelError::Io {
-            message: "failed to execute kernel build",
-            error: err,
-        })?;
+        let log_path = self.log_path("kernel-build.log")?;
+        let mut cmd = kernel_build_command(args, config);
+        let output =
+            logs::output(&mut cmd, &log_path, !quiet).map_err(|err| BuildKernelError::Io {
+                message: "failed to execute kernel build",
+                error: err,
+            })?;
         if !output.status.success() {
             if config.build_command.starts_with(&["xbuild".into()]) {
                 // try executing `cargo xbuild --help` to check whether cargo-xbuild is installed
@@ -91,24 +259,20 @@ impl Builder {
                     }
                 }
             }
-            return Err(BuildKernelError::BuildFailed {
-                stderr: output.stderr,
-            });
+            return Err(BuildKernelError::BuildFailed { log_path });
         }
 
         // Retrieve binary paths
-        let mut cmd = process::Command::new(cargo);
-        cmd.args(&config.build_command);
-        cmd.args(args);
+        let log_path = self.log_path("kernel-build-json.log")?;
+        let mut cmd = kernel_build_command(args, config);
         cmd.arg("--message-format").arg("json");
-        let output = cmd.output().map_err(|err| BuildKernelError::Io {
-            message: "failed to execute kernel build with json output",
-            error: err,
-        })?;
+        let output =
+            logs::output(&mut cmd, &log_path, false).map_err(|err| BuildKernelError::Io {
+                message: "failed to execute kernel build with json output",
+                error: err,
+            })?;
         if !output.status.success() {
-            return Err(BuildKernelError::BuildFailed {
-                stderr: output.stderr,
-            });
+            return Err(BuildKernelError::BuildFailed { log_path });
         }
         let mut executables = Vec::new();
         for line in String::from_utf8(output.stdout)
@@ -117,17 +281,56 @@ impl Builder {
         {
             let mut artifact =
                 json::parse(line).map_err(BuildKernelError::BuildJsonOutputInvalidJson)?;
+            if artifact["reason"] != "compiler-artifact" {
+                continue;
+            }
             if let Some(executable) = artifact["executable"].take_string() {
-                executables.push(PathBuf::from(executable));
+                let package_id = artifact["package_id"].take_string().ok_or_else(|| {
+                    BuildKernelError::BuildJsonOutputIncomplete {
+                        key: "package_id".into(),
+                    }
+                })?;
+                let target = &mut artifact["target"];
+                let target_name = target["name"].take_string().ok_or_else(|| {
+                    BuildKernelError::BuildJsonOutputIncomplete {
+                        key: "target.name".into(),
+                    }
+                })?;
+                let target_kind = target["kind"][0].take_string().ok_or_else(|| {
+                    BuildKernelError::BuildJsonOutputIncomplete {
+                        key: "target.kind".into(),
+                    }
+                })?;
+                executables.push(KernelExecutable {
+                    path: PathBuf::from(executable),
+                    package_id,
+                    target_name,
+                    target_kind,
+                    test: artifact["profile"]["test"].as_bool().unwrap_or(false),
+                });
             }
         }
 
         Ok(executables)
     }
 
+    /// Describes the commands that [`Builder::build_kernel`] would execute for the given
+    /// arguments, without executing them.
+    pub fn plan_build_kernel(&self, args: &[String], config: &Config) -> Vec<CommandDescription> {
+        let cmd = kernel_build_command(args, config);
+        let mut json_cmd = kernel_build_command(args, config);
+        json_cmd.arg("--message-format").arg("json");
+        vec![
+            CommandDescription::new("kernel build", &cmd, false),
+            CommandDescription::new("kernel build (retrieve executable paths)", &json_cmd, false),
+        ]
+    }
+
     /// Creates a bootimage by combining the given kernel binary with the bootloader.
     ///
-    /// Places the resulting bootable disk image at the given `output_bin_path`.
+    /// Places the resulting bootable disk image at the given `output_bin_path`. The given
+    /// `config` should be the configuration of the kernel package, it specifies how the
+    /// bootloader is built.
     ///
     /// If the quiet argument is set to true, all output to stdout is suppressed.
     pub fn create_bootimage(
@@ -135,44 +338,85 @@ impl Builder {
         kernel_manifest_path: &Path,
         bin_path: &Path,
         output_bin_path: &Path,
+        config: &Config,
+        quiet: bool,
+    ) -> Result<(), CreateBootimageError> {
+        let result = self.create_bootimage_inner(
+            kernel_manifest_path,
+            bin_path,
+            output_bin_path,
+            config,
+            quiet,
+        );
+        if interrupt::interrupted() {
+            return Err(CreateBootimageError::Interrupted);
+        }
+        result
+    }
+
+    fn create_bootimage_inner(
+        &mut self,
+        kernel_manifest_path: &Path,
+        bin_path: &Path,
+        output_bin_path: &Path,
+        config: &Config,
         quiet: bool,
     ) -> Result<(), CreateBootimageError> {
+        // use separate log files for each kernel executable
+        let log_suffix = bin_path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "kernel".into());
+        let disk_image_log_path = self.log_path(&format!("disk-image-{}.log", log_suffix))?;
+
+        if let Some(prebuilt_path) = &config.bootloader_prebuilt {
+            if !quiet {
+                println!("Using prebuilt bootloader `{}`", prebuilt_path.display());
+            }
+            disk_image::create_disk_image_from_prebuilt(
+                prebuilt_path,
+                config.bootloader_prebuilt_linker_script.as_deref(),
+                &config.bootloader_prebuilt_fields,
+                bin_path,
+                output_bin_path,
+                &disk_image_log_path,
+            )?;
+            return Ok(());
+        }
+
         let bootloader_build_config = bootloader::BuildConfig::from_metadata(
             self.project_metadata()?,
             kernel_manifest_path,
             bin_path,
+            config,
         )?;
 
         // build bootloader
         if !quiet {
             println!("Building bootloader");
         }
+        let log_path = self.log_path(&format!("bootloader-build-{}.log", log_suffix))?;
         let mut cmd = bootloader_build_config.build_command();
-        if !quiet {
-            cmd.stdout(process::Stdio::inherit());
-            cmd.stderr(process::Stdio::inherit());
-        }
-        let output = cmd.output().map_err(|err| CreateBootimageError::Io {
-            message: "failed to execute bootloader build command",
-            error: err,
-        })?;
+        let output =
+            logs::output(&mut cmd, &log_path, !quiet).map_err(|err| CreateBootimageError::Io {
+                message: "failed to execute bootloader build command",
+                error: err,
+            })?;
         if !output.status.success() {
-            return Err(CreateBootimageError::BootloaderBuildFailed {
-                stderr: output.stderr,
-            });
+            return Err(CreateBootimageError::BootloaderBuildFailed { log_path });
         }
 
         // Retrieve binary path
+        let log_path = self.log_path(&format!("bootloader-build-json-{}.log", log_suffix))?;
         let mut cmd = bootloader_build_config.build_command();
         cmd.arg("--message-format").arg("json");
-        let output = cmd.output().map_err(|err| CreateBootimageError::Io {
-            message: "failed to execute bootloader build command with json output",
-            error: err,
-        })?;
+        let output =
+            logs::output(&mut cmd, &log_path, false).map_err(|err| CreateBootimageError::Io {
+                message: "failed to execute bootloader build command with json output",
+                error: err,
+            })?;
         if !output.status.success() {
-            return Err(CreateBootimageError::BootloaderBuildFailed {
-                stderr: output.stderr,
-            });
+            return Err(CreateBootimageError::BootloaderBuildFailed { log_path });
         }
         let mut bootloader_elf_path = None;
         for line in String::from_utf8(output.stdout)
@@ -197,23 +441,191 @@ impl Builder {
             BootloaderError::BootloaderInvalid("bootloader has no executable".into())
         })?;
 
-        disk_image::create_disk_image(&bootloader_elf_path, output_bin_path)?;
+        disk_image::create_disk_image(&bootloader_elf_path, output_bin_path, &disk_image_log_path)?;
 
         Ok(())
     }
 
-    /// Returns the cargo metadata package that contains the given binary.
-    pub fn kernel_package_for_bin(
+    /// Describes the commands that [`Builder::create_bootimage`] would execute for the given
+    /// arguments, without executing them.
+    ///
+    /// The path of the bootloader executable is predicted since it is only known after the
+    /// bootloader build.
+    pub fn plan_create_bootimage(
         &mut self,
-        kernel_bin_name: &str,
-    ) -> Result<Option<&cargo_metadata::Package>, cargo_metadata::Error> {
-        Ok(self.project_metadata()?.packages.iter().find(|p| {
-            p.targets
+        kernel_manifest_path: &Path,
+        bin_path: &Path,
+        output_bin_path: &Path,
+        config: &Config,
+    ) -> Result<Vec<CommandDescription>, CreateBootimageError> {
+        if let Some(prebuilt_path) = &config.bootloader_prebuilt {
+            let commands = disk_image::prebuilt_commands(
+                prebuilt_path,
+                config.bootloader_prebuilt_linker_script.as_deref(),
+                bin_path,
+                output_bin_path,
+            )?;
+            return Ok(commands
                 .iter()
-                .any(|t| t.name == kernel_bin_name && t.kind.iter().any(|k| k == "bin"))
+                .map(|(description, cmd)| CommandDescription::new(description, cmd, false))
+                .collect());
+        }
+
+        let bootloader_build_config = bootloader::BuildConfig::from_metadata(
+            self.project_metadata()?,
+            kernel_manifest_path,
+            bin_path,
+            config,
+        )?;
+        let cmd = bootloader_build_config.build_command();
+        let mut json_cmd = bootloader_build_config.build_command();
+        json_cmd.arg("--message-format").arg("json");
+        let objcopy = disk_image::objcopy_command(
+            &bootloader_build_config.executable_path(),
+            output_bin_path,
+        )?;
+        Ok(vec![
+            CommandDescription::new("bootloader build", &cmd, true),
+            CommandDescription::new(
+                "bootloader build (retrieve executable path)",
+                &json_cmd,
+                true,
+            ),
+            CommandDescription::new("disk image", &objcopy, false),
+        ])
+    }
+
+    /// Returns the cargo metadata package that the given executable belongs to.
+    ///
+    /// The package is identified by the package id of the executable, so this also works for
+    /// workspaces in which multiple packages have targets with the same name.
+    pub fn kernel_package(
+        &mut self,
+        executable: &KernelExecutable,
+    ) -> Result<Option<&cargo_metadata::Package>, cargo_metadata::Error> {
+        Ok(self
+            .project_metadata()?
+            .packages
+            .iter()
+            .find(|p| p.id.repr == executable.package_id))
+    }
+
+    /// Returns the [`KernelExecutable`] for an executable of the package with the given
+    /// manifest, as [`Builder::build_kernel`] would report it.
+    ///
+    /// This is used when only the path of the executable is known, e.g. in `bootimage runner`.
+    /// The cargo target is looked up by the name of the executable (see
+    /// [`test_name_from_executable_path`]). If a library and a binary have the same name, the
+    /// dep-info file of the executable decides which one it was built from. Returns `None` if
+    /// no target of the package matches, e.g. for doctests.
+    pub fn kernel_executable_from_path(
+        &mut self,
+        kernel_manifest_path: &Path,
+        executable: &Path,
+    ) -> Result<Option<KernelExecutable>, cargo_metadata::Error> {
+        let package = match self
+            .project_metadata()?
+            .packages
+            .iter()
+            .find(|p| p.manifest_path == kernel_manifest_path)
+        {
+            Some(package) => package,
+            None => return Ok(None),
+        };
+        let dir = match executable.parent() {
+            Some(dir) => dir,
+            None => return Ok(None),
+        };
+        // test executables are placed in `deps`, examples in `examples`, binaries directly
+        // in the profile directory
+        let (in_deps, kinds): (bool, &[&str]) = if dir.ends_with("deps") {
+            (true, &["test", "bench", "lib", "bin"])
+        } else if dir.ends_with("examples") {
+            (false, &["example"])
+        } else {
+            (false, &["bin"])
+        };
+        let name = match test_name_from_executable_path(executable) {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        let normalize = |name: &str| name.replace('-', "_");
+        let mut targets: Vec<_> = package
+            .targets
+            .iter()
+            .filter(|t| t.kind.first().is_some_and(|k| kinds.contains(&k.as_str())))
+            .filter(|t| normalize(&t.name) == normalize(&name))
+            .collect();
+        if targets.len() > 1 {
+            let dep_info =
+                std::fs::read_to_string(executable.with_extension("d")).unwrap_or_default();
+            targets.retain(|t| dep_info.contains(&*t.src_path.to_string_lossy()));
+        }
+        let target = match targets.as_slice() {
+            [target] => target,
+            _ => return Ok(None),
+        };
+        let target_kind = target.kind[0].clone();
+        Ok(Some(KernelExecutable {
+            path: executable.to_owned(),
+            package_id: package.id.repr.clone(),
+            // cargo also reports test targets with `harness = false` as tests
+            test: in_deps,
+            target_name: target.name.clone(),
+            target_kind,
         }))
     }
 
+    /// Returns the kernel packages of the project.
+    ///
+    /// If the project manifest belongs to a package, only this package is returned. For virtual
+    /// workspace manifests, all workspace members that depend on a `bootloader` crate are
+    /// returned.
+    pub fn kernel_packages(
+        &mut self,
+    ) -> Result<Vec<&cargo_metadata::Package>, cargo_metadata::Error> {
+        let manifest_path = self.manifest_path.clone();
+        let metadata = self.project_metadata()?;
+        if let Some(package) = metadata
+            .packages
+            .iter()
+            .find(|p| p.manifest_path == manifest_path)
+        {
+            return Ok(vec![package]);
+        }
+        Ok(metadata
+            .packages
+            .iter()
+            .filter(|p| metadata.workspace_members.contains(&p.id))
+            .filter(|p| has_bootloader_dependency(p))
+            .collect())
+    }
+
+    /// Returns the path to the target directory of the project.
+    pub fn target_dir(&mut self) -> Result<&Path, cargo_metadata::Error> {
+        Ok(&self.project_metadata()?.target_directory)
+    }
+
+    /// Returns the path of the log file with the given name, creating the log directory
+    /// if necessary.
+    fn log_path(&mut self, file_name: &str) -> Result<PathBuf, LogDirError> {
+        if let Some(log_dir) = &self.log_dir {
+            return Ok(log_dir.join(file_name));
+        }
+        let name = match &self.log_name {
+            Some(name) => name.clone(),
+            None => self
+                .manifest_path
+                .parent()
+                .and_then(Path::file_name)
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "kernel".into()),
+        };
+        let target_dir = self.project_metadata()?.target_directory.clone();
+        let log_dir = logs::create_log_dir(&target_dir, &name, self.log_retention)?;
+        Ok(self.log_dir.get_or_insert(log_dir).join(file_name))
+    }
+
     fn project_metadata(&mut self) -> Result<&Metadata, cargo_metadata::Error> {
         if let Some(ref metadata) = self.project_metadata {
             return Ok(metadata);
@@ -1,9 +1,29 @@
 //! Parses the `package.metadata.bootimage` configuration table
 
 use anyhow::{anyhow, Context, Result};
-use std::path::Path;
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    path::{Path, PathBuf},
+};
 use toml::Value;
 
+/// The default template for the file names of disk images.
+pub const DEFAULT_IMAGE_NAME: &str = "bootimage-{bin}.{format}";
+
+/// The default command that is invoked with the created bootimage.
+pub const DEFAULT_RUN_COMMAND: &[&str] = &["qemu-system-x86_64", "-drive", "format=raw,file={}"];
+
+/// The default number of log directories that are kept in `target/bootimage/logs`.
+pub const DEFAULT_LOG_RETENTION: u32 = 100;
+
+/// The format of the created disk images, used for the `{format}` placeholder.
+pub const IMAGE_FORMAT: &str = "bin";
+
+/// The placeholders that are supported in `image-name` templates.
+const IMAGE_NAME_PLACEHOLDERS: &[&str] =
+    &["package", "bin", "version", "target", "profile", "format"];
+
 /// Represents the `package.metadata.bootimage` configuration table
 ///
 /// The bootimage crate can be configured through a `package.metadata.bootimage` table
@@ -20,6 +40,17 @@ pub struct Config {
     ///
     /// The substring "{}" will be replaced with the path to the bootable disk image.
     pub run_command: Vec<String>,
+    /// Typed QEMU options, defined in a `package.metadata.bootimage.qemu` table
+    ///
+    /// If set, the run command is created from these options instead of `run_command`. Set
+    /// to `None` if a `run-command` is configured, which takes precedence.
+    pub qemu: Option<QemuConfig>,
+    /// A device that the kernel uses to exit QEMU with an exit code
+    ///
+    /// The device is added to the QEMU command line of runs and tests automatically. The
+    /// exit codes in the configuration (e.g. `test_success_exit_code`) are the values
+    /// written by the kernel, not the exit codes of QEMU. Defaults to `None`.
+    pub exit_device: Option<ExitDevice>,
     /// Additional arguments passed to the runner for not-test binaries
     ///
     /// Applies to `bootimage run` and `bootimage runner`.
@@ -33,10 +64,428 @@ pub struct Config {
     /// An exit code that should be considered as success for test executables (applies to
     /// `bootimage runner`)
     pub test_success_exit_code: Option<i32>,
+    /// The outcomes of specific QEMU exit codes of test executables
+    ///
+    /// [`Config::test_success_exit_code`] is treated like an additional `pass` entry. Exit codes
+    /// without an entry are treated as failures if any outcome or success exit code is
+    /// configured. Use [`Config::test_outcome`] to look up an exit code.
+    pub test_exit_codes: BTreeMap<i32, TestOutcome>,
     /// Whether the `-no-reboot` flag should be passed to test executables
     ///
     /// Defaults to `true`
     pub test_no_reboot: bool,
+    /// How often a failed or timed out test executable is rerun before it's reported as failed
+    ///
+    /// A test executable that passes on a rerun is reported as flaky. Defaults to `0`.
+    pub test_retries: u32,
+    /// Whether the state of QEMU is captured through a QMP socket before a timed out test
+    /// executable is killed
+    ///
+    /// Only used if the run command is created by bootimage, i.e. for the `qemu` table and the
+    /// default `run_command`, since custom run commands might not accept the QMP arguments.
+    /// Only supported on Unix. Defaults to `true`.
+    pub test_timeout_diagnostics: bool,
+    /// How many log directories are kept in `target/bootimage/logs`
+    ///
+    /// The oldest directories are removed when a new one is created. `0` keeps all
+    /// directories. Defaults to [`DEFAULT_LOG_RETENTION`].
+    pub log_retention: u32,
+    /// Whether and how the QEMU output of non-test executables is written to a log file
+    ///
+    /// Defaults to `None`, i.e. the output is not captured.
+    pub run_serial_log: Option<SerialLogConfig>,
+    /// Whether and how the QEMU output of test executables is written to a log file
+    ///
+    /// Defaults to `None`, i.e. the output is not captured.
+    pub test_serial_log: Option<SerialLogConfig>,
+    /// The path of a JUnit XML report that the results of test executables are written to
+    ///
+    /// Relative paths are interpreted relative to the `Cargo.toml`. Defaults to `None`.
+    pub test_report: Option<PathBuf>,
+    /// The expected serial output of test executables
+    ///
+    /// Defaults to `None`, i.e. the output is not checked.
+    pub expected_output: Option<ExpectedOutputConfig>,
+    /// Flags that are passed to `rustc` when building the bootloader
+    ///
+    /// They are passed through the `CARGO_ENCODED_RUSTFLAGS` environment variable, so any
+    /// `RUSTFLAGS` or `rustflags` settings of the caller are ignored. For `cargo-xbuild`,
+    /// which only reads `RUSTFLAGS`, they are joined with spaces, so flags that contain
+    /// spaces are split. Defaults to no flags.
+    pub bootloader_rustflags: Vec<String>,
+    /// Additional environment variables that are set for the bootloader build
+    pub bootloader_env: BTreeMap<String, String>,
+    /// The cargo profile that is used for building the bootloader
+    ///
+    /// Defaults to `release`. Custom profiles need to be defined in the bootloader's Cargo.toml.
+    pub bootloader_profile: String,
+    /// The cargo features that are enabled for the bootloader build
+    ///
+    /// Defaults to the features that the kernel enables for its `bootloader` dependency. The
+    /// `binary` feature is always added if the bootloader defines it.
+    pub bootloader_features: Option<Vec<String>>,
+    /// A prebuilt bootloader that is used instead of building the bootloader dependency
+    ///
+    /// Either a static archive or relocatable object file of the bootloader without the kernel,
+    /// which is linked with the kernel, a bootloader ELF file that was linked with
+    /// `--emit-relocs`, whose kernel is replaced, or a flat bootloader binary, to which the
+    /// kernel is appended. Relative paths are resolved against the directory of the kernel's
+    /// Cargo.toml.
+    pub bootloader_prebuilt: Option<PathBuf>,
+    /// The linker script for linking a prebuilt bootloader archive with the kernel
+    ///
+    /// Defaults to a `linker.ld` file next to the `bootloader_prebuilt` file.
+    pub bootloader_prebuilt_linker_script: Option<PathBuf>,
+    /// The fields of a flat prebuilt bootloader binary that are set to the location and size
+    /// of the kernel
+    ///
+    /// Flat binaries have no symbols, so the fields that the bootloader reads must be listed
+    /// explicitly. Linked ELF files are patched through their relocations instead.
+    pub bootloader_prebuilt_fields: Vec<PrebuiltField>,
+    /// The template for the file names of the created disk images
+    ///
+    /// Supports the `{package}`, `{bin}`, `{version}`, `{target}`, `{profile}`, and `{format}`
+    /// placeholders. Defaults to [`DEFAULT_IMAGE_NAME`].
+    pub image_name: String,
+    /// The directory in which the disk images are placed
+    ///
+    /// Defaults to the directory of the kernel executable. Relative paths are resolved against
+    /// the directory of the kernel's Cargo.toml.
+    pub out_dir: Option<PathBuf>,
+    /// Overrides for specific cargo profiles, defined in `package.metadata.bootimage.profile.<name>`
+    /// tables
+    ///
+    /// Use [`Config::for_profile`] to apply them.
+    pub profile_overrides: BTreeMap<String, ProfileConfig>,
+    /// Overrides for specific test executables, defined in
+    /// `package.metadata.bootimage.test.<name>` tables
+    ///
+    /// Use [`Config::for_test`] to apply them.
+    pub test_overrides: BTreeMap<String, TestConfig>,
+    /// Notes about the configuration that are printed before running a disk image, e.g.
+    /// about keys that are ignored
+    pub notes: Vec<String>,
+}
+
+impl Config {
+    /// Returns whether the run command is a QEMU command created by bootimage, i.e. the
+    /// command of the `qemu` table or the default `run_command`.
+    pub fn runs_builtin_qemu(&self) -> bool {
+        self.qemu.is_some() || self.run_command == DEFAULT_RUN_COMMAND
+    }
+
+    /// Returns the path of the disk image for the given kernel executable.
+    ///
+    /// The file name is created from the `image_name` template. The image is placed in the
+    /// `out_dir` if set, otherwise in the given `executable_dir`.
+    pub fn image_path(&self, vars: &ImageNameVars, executable_dir: &Path) -> PathBuf {
+        let file_name = self
+            .image_name
+            .replace("{package}", &vars.package)
+            .replace("{bin}", &vars.bin)
+            .replace("{version}", &vars.version)
+            .replace("{target}", &vars.target)
+            .replace("{profile}", &vars.profile)
+            .replace("{format}", IMAGE_FORMAT);
+        self.out_dir
+            .as_deref()
+            .unwrap_or(executable_dir)
+            .join(file_name)
+    }
+
+    /// Returns the outcome of a test executable that exited QEMU with the given exit code.
+    ///
+    /// Returns `None` if the exit code has no configured outcome.
+    pub fn test_outcome(&self, qemu_exit_code: i32) -> Option<TestOutcome> {
+        match self.test_exit_codes.get(&qemu_exit_code) {
+            Some(outcome) => Some(*outcome),
+            None if self.test_success_exit_code == Some(qemu_exit_code) => Some(TestOutcome::Pass),
+            None => None,
+        }
+    }
+
+    /// Returns the configuration with the overrides for the given cargo profile applied.
+    pub fn for_profile(&self, profile: &str) -> Config {
+        let mut config = self.clone();
+        if let Some(overrides) = self.profile_overrides.get(profile) {
+            if let Some(profile) = &overrides.bootloader_profile {
+                config.bootloader_profile = profile.clone();
+            }
+            if let Some(features) = &overrides.bootloader_features {
+                config.bootloader_features = Some(features.clone());
+            }
+            if let Some(rustflags) = &overrides.bootloader_rustflags {
+                config.bootloader_rustflags = rustflags.clone();
+            }
+            if let Some(env) = &overrides.bootloader_env {
+                config.bootloader_env = env.clone();
+            }
+        }
+        config
+    }
+
+    /// Returns the configuration with the overrides for the given test executable applied.
+    ///
+    /// The name is the name of the test target (see
+    /// [`test_name_from_executable_path`](crate::builder::test_name_from_executable_path)).
+    /// Dashes and underscores are treated as equal, since cargo replaces dashes in the names
+    /// of test executables.
+    pub fn for_test(&self, name: &str) -> Config {
+        let mut config = self.clone();
+        let normalize = |name: &str| name.replace('-', "_");
+        let overrides = self
+            .test_overrides
+            .iter()
+            .find(|(test, _)| normalize(test) == normalize(name));
+        if let Some((_, overrides)) = overrides {
+            if let Some(timeout) = overrides.test_timeout {
+                config.test_timeout = timeout;
+            }
+            if let Some(args) = &overrides.test_args {
+                config.test_args = Some(args.clone());
+            }
+            if let Some(exit_code) = overrides.test_success_exit_code {
+                config.test_success_exit_code = Some(exit_code);
+            }
+            if let Some(exit_codes) = &overrides.test_exit_codes {
+                config.test_exit_codes = exit_codes.clone();
+            }
+            if let Some(no_reboot) = overrides.test_no_reboot {
+                config.test_no_reboot = no_reboot;
+            }
+            if let Some(retries) = overrides.test_retries {
+                config.test_retries = retries;
+            }
+            if let Some(expected_output) = &overrides.expected_output {
+                config.expected_output = Some(expected_output.clone());
+            }
+        }
+        config
+    }
+}
+
+/// The values for the placeholders of an `image-name` template
+#[derive(Debug, Clone)]
+pub struct ImageNameVars {
+    /// The name of the kernel package
+    pub package: String,
+    /// The name of the kernel binary
+    pub bin: String,
+    /// The version of the kernel package
+    pub version: String,
+    /// The name of the compilation target, or `host` if no target was specified
+    pub target: String,
+    /// The name of the cargo profile
+    pub profile: String,
+}
+
+/// Represents the `package.metadata.bootimage.qemu` configuration table
+///
+/// All keys are optional. Keys that are not set don't add any arguments, so that the QEMU
+/// defaults apply.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct QemuConfig {
+    /// The QEMU executable, defaults to `qemu-system-x86_64`
+    pub binary: Option<String>,
+    /// The guest memory size, passed as `-m` (e.g. `512M`)
+    pub memory: Option<String>,
+    /// The number of CPUs, passed as `-smp`
+    pub smp: Option<u32>,
+    /// The machine type, passed as `-machine` (e.g. `q35`)
+    pub machine: Option<String>,
+    /// The CPU model, passed as `-cpu`
+    pub cpu: Option<String>,
+    /// The display type, passed as `-display` (e.g. `none`)
+    pub display: Option<String>,
+    /// The serial port backend, passed as `-serial` (e.g. `stdio`)
+    pub serial: Option<String>,
+    /// Devices that are added through `-device` arguments
+    pub devices: Vec<String>,
+    /// The accelerator, passed as `-accel` (e.g. `kvm` or `tcg`)
+    ///
+    /// The special value `auto` uses `kvm` together with `-cpu host` if `/dev/kvm` is
+    /// accessible and `tcg` otherwise.
+    pub accel: Option<String>,
+}
+
+/// A device that allows the kernel to exit QEMU with an exit code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ExitDevice {
+    /// The `isa-debug-exit` device of x86 QEMU
+    ///
+    /// Writing a value to the I/O port at `iobase` exits QEMU with exit code
+    /// `(value << 1) | 1`.
+    IsaDebugExit {
+        /// The I/O port of the device
+        iobase: u16,
+        /// The size of the I/O port in bytes
+        iosize: u16,
+    },
+}
+
+impl ExitDevice {
+    /// Returns the QEMU arguments that add the device.
+    pub fn qemu_args(&self) -> Vec<String> {
+        match self {
+            ExitDevice::IsaDebugExit { iobase, iosize } => vec![
+                "-device".into(),
+                format!("isa-debug-exit,iobase={:#x},iosize={:#04x}", iobase, iosize),
+            ],
+        }
+    }
+
+    /// Translates the QEMU exit code back to the value that the kernel wrote to the device.
+    ///
+    /// Returns `None` if QEMU exited without a write to the device (e.g. on a normal
+    /// shutdown or an error of QEMU itself). Note that QEMU errors also use exit code 1,
+    /// which can't be distinguished from a write of the value 0.
+    pub fn guest_exit_code(&self, qemu_exit_code: i32) -> Option<i32> {
+        match self {
+            ExitDevice::IsaDebugExit { .. } if qemu_exit_code & 1 == 1 => Some(qemu_exit_code >> 1),
+            ExitDevice::IsaDebugExit { .. } => None,
+        }
+    }
+}
+
+/// A field of a flat prebuilt bootloader binary that is set to a property of the kernel,
+/// defined in the `bootloader-prebuilt-fields` array
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PrebuiltField {
+    /// The byte offset of the field in the bootloader binary
+    pub offset: u64,
+    /// The size of the field in bytes (1, 2, 4, or 8)
+    ///
+    /// Fields are written in little endian byte order.
+    pub width: u8,
+    /// The property of the kernel that is written to the field
+    pub value: PrebuiltFieldValue,
+    /// A constant that is added to the value, e.g. the load address of the bootloader for
+    /// fields that contain addresses
+    ///
+    /// Defaults to 0.
+    pub add: i64,
+}
+
+/// The property of the kernel that is written to a [`PrebuiltField`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrebuiltFieldValue {
+    /// The byte offset of the kernel in the disk image (`kernel-offset`)
+    KernelOffset,
+    /// The byte offset of the end of the kernel in the disk image (`kernel-end`)
+    KernelEnd,
+    /// The size of the kernel in bytes (`kernel-size`)
+    KernelSize,
+    /// The number of the first 512-byte block of the kernel (`kernel-block`)
+    KernelBlock,
+    /// The size of the kernel in 512-byte blocks, rounded up (`kernel-blocks`)
+    KernelBlocks,
+}
+
+/// Settings for writing the serial output of QEMU to a log file
+///
+/// The serial output is the standard output of QEMU, e.g. through `-serial stdio`.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct SerialLogConfig {
+    /// Whether each line of the log file is prefixed with the number of seconds since QEMU
+    /// was started
+    ///
+    /// Defaults to `false`.
+    pub timestamps: bool,
+    /// Whether ANSI escape sequences (e.g. colors) are removed from the log file
+    ///
+    /// Defaults to `true`. The output on the terminal is not changed.
+    pub strip_ansi: bool,
+}
+
+impl Default for SerialLogConfig {
+    fn default() -> Self {
+        SerialLogConfig {
+            timestamps: false,
+            strip_ansi: true,
+        }
+    }
+}
+
+/// The outcome of a test executable, as configured for a QEMU exit code in the
+/// `test-exit-codes` table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestOutcome {
+    /// The test passed (`pass`)
+    Pass,
+    /// The test failed (`fail`)
+    Fail,
+    /// The test was skipped, e.g. because the hardware doesn't support it (`skip`)
+    Skip,
+    /// The test panicked as expected, e.g. for `should_panic` tests (`expected-panic`)
+    ExpectedPanic,
+}
+
+/// Settings for comparing the serial output of test executables against a file with the
+/// expected output
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ExpectedOutputConfig {
+    /// The path of the file with the expected output
+    ///
+    /// Relative paths in the `Cargo.toml` are resolved relative to its directory. The
+    /// `{test}` placeholder is replaced with the name of the test executable, i.e. its
+    /// file stem without the hash suffix added by cargo.
+    pub path: String,
+    /// Whether output lines that don't match any expected line are ignored
+    ///
+    /// Defaults to `false`, i.e. the output must match the expected lines exactly.
+    pub ignore_unmatched: bool,
+}
+
+impl ExpectedOutputConfig {
+    /// Returns the path of the expected output file for the given test executable name.
+    pub fn path_for(&self, test: &str) -> PathBuf {
+        PathBuf::from(self.path.replace("{test}", test))
+    }
+}
+
+/// Represents a `package.metadata.bootimage.profile.<name>` configuration table
+///
+/// All keys are optional and override the corresponding key of the main configuration when
+/// the kernel is built with the given cargo profile.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct ProfileConfig {
+    /// Overrides [`Config::bootloader_profile`]
+    pub bootloader_profile: Option<String>,
+    /// Overrides [`Config::bootloader_features`]
+    pub bootloader_features: Option<Vec<String>>,
+    /// Overrides [`Config::bootloader_rustflags`]
+    pub bootloader_rustflags: Option<Vec<String>>,
+    /// Overrides [`Config::bootloader_env`]
+    pub bootloader_env: Option<BTreeMap<String, String>>,
+}
+
+/// Represents a `package.metadata.bootimage.test.<name>` configuration table
+///
+/// All keys are optional and override the corresponding key of the main configuration for
+/// the test executable with the given name.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct TestConfig {
+    /// Overrides [`Config::test_timeout`]
+    pub test_timeout: Option<u32>,
+    /// Overrides [`Config::test_args`]
+    pub test_args: Option<Vec<String>>,
+    /// Overrides [`Config::test_success_exit_code`]
+    pub test_success_exit_code: Option<i32>,
+    /// Overrides [`Config::test_exit_codes`]
+    pub test_exit_codes: Option<BTreeMap<i32, TestOutcome>>,
+    /// Overrides [`Config::test_no_reboot`]
+    pub test_no_reboot: Option<bool>,
+    /// Overrides [`Config::test_retries`]
+    pub test_retries: Option<u32>,
+    /// Overrides [`Config::expected_output`]
+    pub expected_output: Option<ExpectedOutputConfig>,
 }
 
 /// Reads the configuration from a `package.metadata.bootimage` in the given Cargo.toml.
@@ -71,18 +520,21 @@ fn read_config_inner(manifest_path: &Path) -> Result<Config> {
     };
 
     let mut config = ConfigBuilder::default();
+    let manifest_dir = manifest_path
+        .parent()
+        .ok_or_else(|| anyhow!("Cargo.toml path has no parent directory"))?;
 
     for (key, value) in metadata {
         match (key.as_str(), value.clone()) {
-            ("test-timeout", Value::Integer(timeout)) if timeout.is_negative() => {
-                return Err(anyhow!("test-timeout must not be negative"))
-            }
             ("test-timeout", Value::Integer(timeout)) => {
-                config.test_timeout = Some(timeout as u32);
+                config.test_timeout = Some(parse_test_timeout(timeout)?);
             }
             ("test-success-exit-code", Value::Integer(exit_code)) => {
                 config.test_success_exit_code = Some(exit_code as i32);
             }
+            ("test-exit-codes", Value::Table(table)) => {
+                config.test_exit_codes = Some(parse_test_exit_codes(table)?);
+            }
             ("build-command", Value::Array(array)) => {
                 config.build_command = Some(parse_string_array(array, "build-command")?);
             }
@@ -98,6 +550,124 @@ fn read_config_inner(manifest_path: &Path) -> Result<Config> {
             ("test-no-reboot", Value::Boolean(no_reboot)) => {
                 config.test_no_reboot = Some(no_reboot);
             }
+            ("test-retries", Value::Integer(retries)) => {
+                config.test_retries = Some(parse_test_retries(retries)?);
+            }
+            ("test-timeout-diagnostics", Value::Boolean(diagnostics)) => {
+                config.test_timeout_diagnostics = Some(diagnostics);
+            }
+            ("log-retention", Value::Integer(retention)) => {
+                config.log_retention = Some(u32::try_from(retention).map_err(|_| {
+                    anyhow!(
+                        "`log-retention` must not be negative, found `{}`",
+                        retention
+                    )
+                })?);
+            }
+            ("bootloader-rustflags", Value::Array(array)) => {
+                config.bootloader_rustflags =
+                    Some(parse_string_array(array, "bootloader-rustflags")?);
+            }
+            ("bootloader-env", Value::Table(table)) => {
+                config.bootloader_env = Some(parse_string_table(table, "bootloader-env")?);
+            }
+            ("bootloader-profile", Value::String(profile)) => {
+                config.bootloader_profile = Some(profile);
+            }
+            ("bootloader-features", Value::Array(array)) => {
+                config.bootloader_features =
+                    Some(parse_string_array(array, "bootloader-features")?);
+            }
+            ("bootloader-prebuilt", Value::String(path)) => {
+                config.bootloader_prebuilt = Some(manifest_dir.join(path));
+            }
+            ("bootloader-prebuilt-linker-script", Value::String(path)) => {
+                config.bootloader_prebuilt_linker_script = Some(manifest_dir.join(path));
+            }
+            ("bootloader-prebuilt-fields", Value::Array(array)) => {
+                config.bootloader_prebuilt_fields = Some(
+                    array
+                        .into_iter()
+                        .map(parse_prebuilt_field)
+                        .collect::<Result<_>>()
+                        .context("Invalid `bootloader-prebuilt-fields` entry")?,
+                );
+            }
+            ("image-name", Value::String(template)) => {
+                validate_image_name(&template)?;
+                config.image_name = Some(template);
+            }
+            ("out-dir", Value::String(path)) => {
+                config.out_dir = Some(manifest_dir.join(path));
+            }
+            ("test-report", Value::String(path)) => {
+                config.test_report = Some(manifest_dir.join(path));
+            }
+            ("expected-output", value) => {
+                config.expected_output = Some(parse_expected_output(value, manifest_dir)?);
+            }
+            ("test", Value::Table(tests)) => {
+                let mut overrides = BTreeMap::new();
+                for (name, value) in tests {
+                    let table = match value {
+                        Value::Table(table) => table,
+                        other => {
+                            return Err(anyhow!(
+                                "`package.metadata.bootimage.test.{}` must be a table, \
+                                 found `{}`",
+                                name,
+                                other
+                            ))
+                        }
+                    };
+                    let test_config = parse_test_config(table, manifest_dir)
+                        .with_context(|| format!("Invalid test override `{}`", name))?;
+                    overrides.insert(name, test_config);
+                }
+                config.test_overrides = Some(overrides);
+            }
+            ("serial-log", Value::Boolean(enabled)) => {
+                let settings = if enabled {
+                    Some(SerialLogConfig::default())
+                } else {
+                    None
+                };
+                config.run_serial_log = settings.clone();
+                config.test_serial_log = settings;
+            }
+            ("serial-log", Value::Table(table)) => {
+                let (run, test) =
+                    parse_serial_log_config(table).context("Invalid `serial-log` table")?;
+                config.run_serial_log = run;
+                config.test_serial_log = test;
+            }
+            ("exit-device", Value::Table(table)) => {
+                config.exit_device =
+                    Some(parse_exit_device(table).context("Invalid `exit-device` table")?);
+            }
+            ("qemu", Value::Table(table)) => {
+                config.qemu = Some(parse_qemu_config(table).context("Invalid `qemu` table")?);
+            }
+            ("profile", Value::Table(profiles)) => {
+                let mut overrides = BTreeMap::new();
+                for (name, value) in profiles {
+                    let table = match value {
+                        Value::Table(table) => table,
+                        other => {
+                            return Err(anyhow!(
+                                "`package.metadata.bootimage.profile.{}` must be a table, \
+                                 found `{}`",
+                                name,
+                                other
+                            ))
+                        }
+                    };
+                    let profile_config = parse_profile_config(table)
+                        .with_context(|| format!("Invalid profile override `{}`", name))?;
+                    overrides.insert(name, profile_config);
+                }
+                config.profile_overrides = Some(overrides);
+            }
             (key, value) => {
                 return Err(anyhow!(
                     "unexpected `package.metadata.bootimage` \
@@ -108,9 +678,381 @@ fn read_config_inner(manifest_path: &Path) -> Result<Config> {
             }
         }
     }
+    if config.exit_device.is_some() {
+        let test_override_args = config
+            .test_overrides
+            .iter()
+            .flat_map(|overrides| overrides.values())
+            .filter_map(|overrides| overrides.test_args.as_ref());
+        let mut args = config
+            .run_command
+            .iter()
+            .chain(config.qemu.iter().map(|qemu| &qemu.devices))
+            .chain(&config.run_args)
+            .chain(&config.test_args)
+            .chain(test_override_args)
+            .flatten();
+        if args.any(|arg| arg.contains("isa-debug-exit")) {
+            return Err(anyhow!(
+                "the `exit-device` is added to the QEMU command automatically, so it must not \
+                 be specified as a QEMU argument"
+            ));
+        }
+    }
+    if config.qemu.is_some() && config.run_command.is_some() {
+        config.qemu = None;
+        config
+            .notes
+            .push("`run-command` takes precedence over the `qemu` table, which is ignored".into());
+    }
     Ok(config.into())
 }
 
+fn validate_image_name(template: &str) -> Result<()> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("image-name `{}` has an unclosed `{{`", template))?;
+        let placeholder = &rest[start + 1..start + end];
+        if !IMAGE_NAME_PLACEHOLDERS.contains(&placeholder) {
+            return Err(anyhow!(
+                "image-name `{}` contains unknown placeholder `{{{}}}` (supported are: {})",
+                template,
+                placeholder,
+                IMAGE_NAME_PLACEHOLDERS.join(", ")
+            ));
+        }
+        rest = &rest[start + end + 1..];
+    }
+    if template.contains('/') || template.contains('\\') {
+        return Err(anyhow!(
+            "image-name `{}` must be a file name, use out-dir to set the directory",
+            template
+        ));
+    }
+    Ok(())
+}
+
+fn parse_profile_config(table: toml::value::Table) -> Result<ProfileConfig> {
+    let mut config = ProfileConfig::default();
+    for (key, value) in table {
+        match (key.as_str(), value) {
+            ("bootloader-profile", Value::String(profile)) => {
+                config.bootloader_profile = Some(profile);
+            }
+            ("bootloader-features", Value::Array(array)) => {
+                config.bootloader_features =
+                    Some(parse_string_array(array, "bootloader-features")?);
+            }
+            ("bootloader-rustflags", Value::Array(array)) => {
+                config.bootloader_rustflags =
+                    Some(parse_string_array(array, "bootloader-rustflags")?);
+            }
+            ("bootloader-env", Value::Table(table)) => {
+                config.bootloader_env = Some(parse_string_table(table, "bootloader-env")?);
+            }
+            (key, value) => {
+                return Err(anyhow!(
+                    "unexpected key `{}` with value `{}` in profile override",
+                    key,
+                    value
+                ))
+            }
+        }
+    }
+    Ok(config)
+}
+
+/// Parses a `serial-log` table and returns the settings for runs and tests.
+///
+/// The `timestamps` and `strip-ansi` keys of the table apply to both runs and tests. The
+/// `run` and `test` keys default to `true` and can be set to `false` or to a table that
+/// overrides the shared keys.
+fn parse_serial_log_config(
+    table: toml::value::Table,
+) -> Result<(Option<SerialLogConfig>, Option<SerialLogConfig>)> {
+    fn parse_settings(
+        table: &toml::value::Table,
+        mut settings: SerialLogConfig,
+        ignored_keys: &[&str],
+    ) -> Result<SerialLogConfig> {
+        for (key, value) in table {
+            match (key.as_str(), value) {
+                ("timestamps", Value::Boolean(timestamps)) => settings.timestamps = *timestamps,
+                ("strip-ansi", Value::Boolean(strip_ansi)) => settings.strip_ansi = *strip_ansi,
+                (key, _) if ignored_keys.contains(&key) => {}
+                (key, value) => {
+                    return Err(anyhow!("unexpected key `{}` with value `{}`", key, value))
+                }
+            }
+        }
+        Ok(settings)
+    }
+
+    let shared = parse_settings(&table, SerialLogConfig::default(), &["run", "test"])?;
+    let parse_mode = |mode: &str| -> Result<Option<SerialLogConfig>> {
+        match table.get(mode) {
+            None | Some(Value::Boolean(true)) => Ok(Some(shared.clone())),
+            Some(Value::Boolean(false)) => Ok(None),
+            Some(Value::Table(overrides)) => {
+                parse_settings(overrides, shared.clone(), &[]).map(Some)
+            }
+            Some(other) => Err(anyhow!(
+                "`{}` must be a boolean or a table, found `{}`",
+                mode,
+                other
+            )),
+        }
+    };
+    Ok((parse_mode("run")?, parse_mode("test")?))
+}
+
+fn parse_expected_output(value: Value, manifest_dir: &Path) -> Result<ExpectedOutputConfig> {
+    let mut path = None;
+    let mut ignore_unmatched = false;
+    match value {
+        Value::String(p) => path = Some(p),
+        Value::Table(table) => {
+            for (key, value) in table {
+                match (key.as_str(), value) {
+                    ("path", Value::String(p)) => path = Some(p),
+                    ("ignore-unmatched", Value::Boolean(ignore)) => ignore_unmatched = ignore,
+                    (key, value) => {
+                        return Err(anyhow!(
+                            "unexpected key `{}` with value `{}` in `expected-output` table",
+                            key,
+                            value
+                        ))
+                    }
+                }
+            }
+        }
+        other => {
+            return Err(anyhow!(
+                "`expected-output` must be a string or a table, found `{}`",
+                other
+            ))
+        }
+    }
+    let path = path.ok_or_else(|| anyhow!("missing `path` key in `expected-output` table"))?;
+    Ok(ExpectedOutputConfig {
+        path: manifest_dir.join(path).display().to_string(),
+        ignore_unmatched,
+    })
+}
+
+fn parse_test_exit_codes(table: toml::value::Table) -> Result<BTreeMap<i32, TestOutcome>> {
+    let mut exit_codes = BTreeMap::new();
+    for (key, value) in table {
+        let parsed = match key.strip_prefix("0x") {
+            Some(hex) => i32::from_str_radix(hex, 16),
+            None => key.parse(),
+        };
+        let exit_code = parsed.map_err(|_| {
+            anyhow!(
+                "`test-exit-codes` keys must be integer exit codes, found `{}`",
+                key
+            )
+        })?;
+        let outcome = match value.as_str() {
+            Some("pass") => TestOutcome::Pass,
+            Some("fail") => TestOutcome::Fail,
+            Some("skip") => TestOutcome::Skip,
+            Some("expected-panic") => TestOutcome::ExpectedPanic,
+            _ => {
+                return Err(anyhow!(
+                    "invalid outcome `{}` for exit code {} in `test-exit-codes` (expected \
+                     `pass`, `fail`, `skip`, or `expected-panic`)",
+                    value,
+                    key
+                ))
+            }
+        };
+        exit_codes.insert(exit_code, outcome);
+    }
+    Ok(exit_codes)
+}
+
+fn parse_test_retries(retries: i64) -> Result<u32> {
+    u32::try_from(retries).map_err(|_| {
+        anyhow!(
+            "`test-retries` must be between 0 and {}, found `{}`",
+            u32::MAX,
+            retries
+        )
+    })
+}
+
+fn parse_test_timeout(timeout: i64) -> Result<u32> {
+    u32::try_from(timeout).map_err(|_| {
+        anyhow!(
+            "`test-timeout` must be between 0 and {} seconds, found `{}`",
+            u32::MAX,
+            timeout
+        )
+    })
+}
+
+fn parse_test_config(table: toml::value::Table, manifest_dir: &Path) -> Result<TestConfig> {
+    let mut config = TestConfig::default();
+    for (key, value) in table {
+        match (key.as_str(), value) {
+            ("test-timeout", Value::Integer(timeout)) => {
+                config.test_timeout = Some(parse_test_timeout(timeout)?);
+            }
+            ("test-success-exit-code", Value::Integer(exit_code)) => {
+                config.test_success_exit_code = Some(exit_code as i32);
+            }
+            ("test-args", Value::Array(array)) => {
+                config.test_args = Some(parse_string_array(array, "test-args")?);
+            }
+            ("test-exit-codes", Value::Table(table)) => {
+                config.test_exit_codes = Some(parse_test_exit_codes(table)?);
+            }
+            ("test-no-reboot", Value::Boolean(no_reboot)) => {
+                config.test_no_reboot = Some(no_reboot);
+            }
+            ("test-retries", Value::Integer(retries)) => {
+                config.test_retries = Some(parse_test_retries(retries)?);
+            }
+            ("expected-output", value) => {
+                config.expected_output = Some(parse_expected_output(value, manifest_dir)?);
+            }
+            (key, value) => {
+                return Err(anyhow!(
+                    "unexpected key `{}` with value `{}` in test override (supported keys are \
+                     `test-timeout`, `test-args`, `test-success-exit-code`, `test-exit-codes`, \
+                     `test-no-reboot`, `test-retries`, and `expected-output`)",
+                    key,
+                    value
+                ))
+            }
+        }
+    }
+    Ok(config)
+}
+
+fn parse_exit_device(table: toml::value::Table) -> Result<ExitDevice> {
+    let mut kind = None;
+    let mut iobase = None;
+    let mut iosize = 0x04;
+    let parse_port = |key: &str, value: i64| -> Result<u16> {
+        u16::try_from(value)
+            .map_err(|_| anyhow!("`{}` must be a valid I/O port, found `{}`", key, value))
+    };
+    for (key, value) in table {
+        match (key.as_str(), value) {
+            ("kind", Value::String(k)) => kind = Some(k),
+            ("iobase", Value::Integer(value)) => iobase = Some(parse_port("iobase", value)?),
+            ("iosize", Value::Integer(value)) => iosize = parse_port("iosize", value)?,
+            (key, value) => return Err(anyhow!("unexpected key `{}` with value `{}`", key, value)),
+        }
+    }
+    match kind.as_deref() {
+        Some("isa-debug-exit") => Ok(ExitDevice::IsaDebugExit {
+            iobase: iobase.ok_or_else(|| anyhow!("missing `iobase` key"))?,
+            iosize,
+        }),
+        Some(other) => Err(anyhow!(
+            "unsupported exit device kind `{}` (only `isa-debug-exit` is supported)",
+            other
+        )),
+        None => Err(anyhow!("missing `kind` key")),
+    }
+}
+
+fn parse_qemu_config(table: toml::value::Table) -> Result<QemuConfig> {
+    let mut config = QemuConfig::default();
+    for (key, value) in table {
+        match (key.as_str(), value) {
+            ("binary", Value::String(binary)) => config.binary = Some(binary),
+            ("memory", Value::String(memory)) => config.memory = Some(memory),
+            // plain numbers are interpreted as MiB by QEMU
+            ("memory", Value::Integer(memory)) if memory > 0 => {
+                config.memory = Some(memory.to_string())
+            }
+            ("smp", Value::Integer(smp)) => {
+                config.smp = Some(
+                    u32::try_from(smp)
+                        .ok()
+                        .filter(|&smp| smp > 0)
+                        .ok_or_else(|| {
+                            anyhow!("`smp` must be a positive integer, found `{}`", smp)
+                        })?,
+                );
+            }
+            ("machine", Value::String(machine)) => config.machine = Some(machine),
+            ("cpu", Value::String(cpu)) => config.cpu = Some(cpu),
+            ("display", Value::String(display)) => config.display = Some(display),
+            ("serial", Value::String(serial)) => config.serial = Some(serial),
+            ("devices", Value::Array(array)) => {
+                config.devices = parse_string_array(array, "devices")?;
+            }
+            ("accel", Value::String(accel)) => config.accel = Some(accel),
+            (key, value) => {
+                return Err(anyhow!(
+                    "unexpected key `{}` with value `{}` in `qemu` table",
+                    key,
+                    value
+                ))
+            }
+        }
+    }
+    Ok(config)
+}
+
+fn parse_prebuilt_field(value: Value) -> Result<PrebuiltField> {
+    let table = match value {
+        Value::Table(table) => table,
+        other => return Err(anyhow!("expected a table, found `{}`", other)),
+    };
+    let mut offset = None;
+    let mut width = None;
+    let mut field_value = None;
+    let mut add = 0;
+    for (key, value) in table {
+        match (key.as_str(), value) {
+            ("offset", Value::Integer(value)) => {
+                offset =
+                    Some(u64::try_from(value).map_err(|_| {
+                        anyhow!("`offset` must not be negative, found `{}`", value)
+                    })?);
+            }
+            ("width", Value::Integer(value)) if [1, 2, 4, 8].contains(&value) => {
+                width = Some(value as u8);
+            }
+            ("width", value) => {
+                return Err(anyhow!("`width` must be 1, 2, 4, or 8, found `{}`", value))
+            }
+            ("value", Value::String(value)) => {
+                field_value = Some(match value.as_str() {
+                    "kernel-offset" => PrebuiltFieldValue::KernelOffset,
+                    "kernel-end" => PrebuiltFieldValue::KernelEnd,
+                    "kernel-size" => PrebuiltFieldValue::KernelSize,
+                    "kernel-block" => PrebuiltFieldValue::KernelBlock,
+                    "kernel-blocks" => PrebuiltFieldValue::KernelBlocks,
+                    other => {
+                        return Err(anyhow!(
+                            "invalid `value` `{}` (expected `kernel-offset`, `kernel-end`, \
+                             `kernel-size`, `kernel-block`, or `kernel-blocks`)",
+                            other
+                        ))
+                    }
+                });
+            }
+            ("add", Value::Integer(value)) => add = value,
+            (key, value) => return Err(anyhow!("unexpected key `{}` with value `{}`", key, value)),
+        }
+    }
+    Ok(PrebuiltField {
+        offset: offset.ok_or_else(|| anyhow!("missing `offset` key"))?,
+        width: width.ok_or_else(|| anyhow!("missing `width` key (1, 2, 4, or 8)"))?,
+        value: field_value.ok_or_else(|| anyhow!("missing `value` key"))?,
+        add,
+    })
+}
+
 fn parse_string_array(array: Vec<Value>, prop_name: &str) -> Result<Vec<String>> {
     let mut parsed = Vec::new();
     for value in array {
@@ -122,33 +1064,95 @@ fn parse_string_array(array: Vec<Value>, prop_name: &str) -> Result<Vec<String>>
     Ok(parsed)
 }
 
+fn parse_string_table(
+    table: toml::value::Table,
+    prop_name: &str,
+) -> Result<BTreeMap<String, String>> {
+    let mut parsed = BTreeMap::new();
+    for (key, value) in table {
+        match value {
+            Value::String(s) => {
+                parsed.insert(key, s);
+            }
+            _ => return Err(anyhow!("{} must be a table of strings", prop_name)),
+        }
+    }
+    Ok(parsed)
+}
+
 #[derive(Default)]
 struct ConfigBuilder {
     build_command: Option<Vec<String>>,
     run_command: Option<Vec<String>>,
+    qemu: Option<QemuConfig>,
+    exit_device: Option<ExitDevice>,
     run_args: Option<Vec<String>>,
     test_args: Option<Vec<String>>,
     test_timeout: Option<u32>,
     test_success_exit_code: Option<i32>,
+    test_exit_codes: Option<BTreeMap<i32, TestOutcome>>,
     test_no_reboot: Option<bool>,
+    test_retries: Option<u32>,
+    test_timeout_diagnostics: Option<bool>,
+    log_retention: Option<u32>,
+    run_serial_log: Option<SerialLogConfig>,
+    test_serial_log: Option<SerialLogConfig>,
+    test_report: Option<PathBuf>,
+    expected_output: Option<ExpectedOutputConfig>,
+    bootloader_rustflags: Option<Vec<String>>,
+    bootloader_env: Option<BTreeMap<String, String>>,
+    bootloader_profile: Option<String>,
+    bootloader_features: Option<Vec<String>>,
+    bootloader_prebuilt: Option<PathBuf>,
+    bootloader_prebuilt_linker_script: Option<PathBuf>,
+    bootloader_prebuilt_fields: Option<Vec<PrebuiltField>>,
+    image_name: Option<String>,
+    out_dir: Option<PathBuf>,
+    profile_overrides: Option<BTreeMap<String, ProfileConfig>>,
+    test_overrides: Option<BTreeMap<String, TestConfig>>,
+    notes: Vec<String>,
 }
 
-impl Into<Config> for ConfigBuilder {
-    fn into(self) -> Config {
+impl From<ConfigBuilder> for Config {
+    fn from(builder: ConfigBuilder) -> Self {
         Config {
-            build_command: self.build_command.unwrap_or_else(|| vec!["build".into()]),
-            run_command: self.run_command.unwrap_or_else(|| {
-                vec![
-                    "qemu-system-x86_64".into(),
-                    "-drive".into(),
-                    "format=raw,file={}".into(),
-                ]
-            }),
-            run_args: self.run_args,
-            test_args: self.test_args,
-            test_timeout: self.test_timeout.unwrap_or(60 * 5),
-            test_success_exit_code: self.test_success_exit_code,
-            test_no_reboot: self.test_no_reboot.unwrap_or(true),
+            build_command: builder
+                .build_command
+                .unwrap_or_else(|| vec!["build".into()]),
+            run_command: builder
+                .run_command
+                .unwrap_or_else(|| DEFAULT_RUN_COMMAND.iter().map(|&arg| arg.into()).collect()),
+            qemu: builder.qemu,
+            exit_device: builder.exit_device,
+            run_args: builder.run_args,
+            test_args: builder.test_args,
+            test_timeout: builder.test_timeout.unwrap_or(60 * 5),
+            test_success_exit_code: builder.test_success_exit_code,
+            test_exit_codes: builder.test_exit_codes.unwrap_or_default(),
+            test_no_reboot: builder.test_no_reboot.unwrap_or(true),
+            test_retries: builder.test_retries.unwrap_or(0),
+            test_timeout_diagnostics: builder.test_timeout_diagnostics.unwrap_or(true),
+            log_retention: builder.log_retention.unwrap_or(DEFAULT_LOG_RETENTION),
+            run_serial_log: builder.run_serial_log,
+            test_serial_log: builder.test_serial_log,
+            test_report: builder.test_report,
+            expected_output: builder.expected_output,
+            bootloader_rustflags: builder.bootloader_rustflags.unwrap_or_default(),
+            bootloader_env: builder.bootloader_env.unwrap_or_default(),
+            bootloader_profile: builder
+                .bootloader_profile
+                .unwrap_or_else(|| "release".into()),
+            bootloader_features: builder.bootloader_features,
+            bootloader_prebuilt: builder.bootloader_prebuilt,
+            bootloader_prebuilt_linker_script: builder.bootloader_prebuilt_linker_script,
+            bootloader_prebuilt_fields: builder.bootloader_prebuilt_fields.unwrap_or_default(),
+            image_name: builder
+                .image_name
+                .unwrap_or_else(|| DEFAULT_IMAGE_NAME.into()),
+            out_dir: builder.out_dir,
+            profile_overrides: builder.profile_overrides.unwrap_or_default(),
+            test_overrides: builder.test_overrides.unwrap_or_default(),
+            notes: builder.notes,
         }
     }
 }
@@ -1,6 +1,8 @@
 const HELP: &str = include_str!("help.txt");
 const CARGO_BOOTIMAGE_HELP: &str = include_str!("cargo_bootimage_help.txt");
 const RUNNER_HELP: &str = include_str!("runner_help.txt");
+const SETUP_HELP: &str = include_str!("setup_help.txt");
+const TEST_HELP: &str = include_str!("test_help.txt");
 
 /// Prints a general help text.
 pub fn print_help() {
@@ -16,6 +18,16 @@ pub fn print_runner_help() {
     print!("{}", RUNNER_HELP);
 }
 
+/// Prints the help for the `bootimage setup` command.
+pub fn print_setup_help() {
+    print!("{}", SETUP_HELP);
+}
+
+/// Prints the help for the `bootimage test` command.
+pub fn print_test_help() {
+    print!("{}", TEST_HELP);
+}
+
 /// Prints the version of this crate.
 pub fn print_version() {
     println!("bootimage {}", env!("CARGO_PKG_VERSION"));
@@ -0,0 +1,204 @@
+//! Reads and writes the `images.json` index of the disk images created by `cargo bootimage`.
+//!
+//! The index records the kind of each image together with the settings that `bootimage
+//! runner` needs to run it later, e.g. after the images were archived by a CI job. Entries of
+//! earlier builds are kept, so that e.g. `cargo bootimage --tests` followed by `cargo
+//! bootimage --examples` records both sets of images.
+
+use crate::{builder::KernelExecutable, config::Config};
+use anyhow::{anyhow, Context, Result};
+use json::JsonValue;
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+/// A disk image that is recorded in an `images.json` index.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ImageRecord {
+    /// The path of the disk image
+    pub image: PathBuf,
+    /// The path of the kernel executable that the image was created from
+    pub executable: PathBuf,
+    /// The cargo package id of the kernel package
+    pub package_id: String,
+    /// The path of the Cargo.toml of the kernel package
+    pub manifest_path: PathBuf,
+    /// The name of the cargo target, e.g. the name of the binary or integration test
+    pub target_name: String,
+    /// The kind of the cargo target, e.g. `bin`, `test`, `bench`, or `example`
+    pub target_kind: String,
+    /// Whether the image is a test, i.e. whether `test-args` and `test-timeout` apply to it
+    pub test: bool,
+    /// The cargo profile of the kernel executable
+    pub profile: String,
+    /// The `test-args` of the test executable (including the per-test overrides)
+    pub test_args: Option<Vec<String>>,
+    /// The `test-timeout` of the test executable in seconds (including the per-test
+    /// overrides), `None` for non-test images
+    pub test_timeout: Option<u32>,
+}
+
+impl ImageRecord {
+    /// Creates the record for a disk image that was created from the given executable.
+    ///
+    /// The `config` is the configuration of the kernel package, with the overrides for the
+    /// profile and test executable applied.
+    pub fn new(
+        image: &Path,
+        executable: &KernelExecutable,
+        manifest_path: &Path,
+        profile: &str,
+        config: &Config,
+    ) -> Self {
+        // integration tests with `harness = false` are run as tests too
+        let test = executable.test || executable.target_kind == "test";
+        ImageRecord {
+            image: image.to_owned(),
+            executable: executable.path.clone(),
+            package_id: executable.package_id.clone(),
+            manifest_path: manifest_path.to_owned(),
+            target_name: executable.target_name.clone(),
+            target_kind: executable.target_kind.clone(),
+            test,
+            profile: profile.to_owned(),
+            test_args: if test { config.test_args.clone() } else { None },
+            test_timeout: if test {
+                Some(config.test_timeout)
+            } else {
+                None
+            },
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        json::object! {
+            "image" => self.image.display().to_string(),
+            "executable" => self.executable.display().to_string(),
+            "package_id" => self.package_id.clone(),
+            "manifest_path" => self.manifest_path.display().to_string(),
+            "target_name" => self.target_name.clone(),
+            "target_kind" => self.target_kind.clone(),
+            "test" => self.test,
+            "profile" => self.profile.clone(),
+            "test_args" => self.test_args.clone(),
+            "test_timeout" => self.test_timeout,
+        }
+    }
+
+    fn from_json(value: &JsonValue) -> Result<Self> {
+        let string = |key: &str| {
+            value[key]
+                .as_str()
+                .map(String::from)
+                .ok_or_else(|| anyhow!("image entry has no `{}` string", key))
+        };
+        let test_args = match &value["test_args"] {
+            JsonValue::Null => None,
+            JsonValue::Array(args) => Some(
+                args.iter()
+                    .map(|arg| arg.as_str().map(String::from))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| anyhow!("`test_args` of image entry must be strings"))?,
+            ),
+            other => return Err(anyhow!("invalid `test_args` value `{}`", other)),
+        };
+        Ok(ImageRecord {
+            image: string("image")?.into(),
+            executable: string("executable")?.into(),
+            package_id: string("package_id")?,
+            manifest_path: string("manifest_path")?.into(),
+            target_name: string("target_name")?,
+            target_kind: string("target_kind")?,
+            test: value["test"]
+                .as_bool()
+                .ok_or_else(|| anyhow!("image entry has no `test` flag"))?,
+            profile: string("profile")?,
+            test_args,
+            test_timeout: value["test_timeout"].as_u32(),
+        })
+    }
+}
+
+/// Returns the path of the index for the given cargo profile.
+pub fn index_path(target_dir: &Path, profile: &str) -> PathBuf {
+    target_dir
+        .join("bootimage")
+        .join(profile)
+        .join("images.json")
+}
+
+/// Reads all records of the index at the given path.
+pub fn read_index(path: &Path) -> Result<Vec<ImageRecord>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read image index `{}`", path.display()))?;
+    parse_index(&content).with_context(|| format!("Invalid image index `{}`", path.display()))
+}
+
+fn parse_index(content: &str) -> Result<Vec<ImageRecord>> {
+    let index = json::parse(content)?;
+    index["images"]
+        .members()
+        .map(ImageRecord::from_json)
+        .collect()
+}
+
+/// Adds the given records to the index at `path`, creating the index if needed.
+///
+/// Existing records of the same images are replaced, all other records are kept.
+pub fn update_index(path: &Path, records: &[ImageRecord]) -> Result<()> {
+    let mut all_records = match fs::read_to_string(path) {
+        Ok(content) => parse_index(&content)
+            .with_context(|| format!("Invalid image index `{}`", path.display()))?,
+        Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to read image index `{}`", path.display()))
+        }
+    };
+    all_records.retain(|existing| !records.iter().any(|r| r.image == existing.image));
+    all_records.extend(records.iter().cloned());
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context("Failed to create bootimage target directory")?;
+    }
+    let images = all_records.iter().map(ImageRecord::to_json).collect();
+    let index = json::object! { "images" => JsonValue::Array(images) };
+    fs::write(path, index.pretty(4))
+        .with_context(|| format!("Failed to write `{}`", path.display()))
+}
+
+/// Looks up the record of the given disk image in the index at `index_path`.
+///
+/// Images are matched by their path first. Since archived images are usually moved, an
+/// image with the same file name is used otherwise, as long as the file name is unique in
+/// the index.
+pub fn find_image(index_path: &Path, image: &Path) -> Result<ImageRecord> {
+    let records = read_index(index_path)?;
+    let canonical = image.canonicalize().ok();
+    let same_path = |record: &ImageRecord| {
+        record.image == image
+            || (canonical.is_some() && record.image.canonicalize().ok() == canonical)
+    };
+    if let Some(record) = records.iter().find(|r| same_path(r)) {
+        return Ok(record.clone());
+    }
+    let mut same_name = records
+        .into_iter()
+        .filter(|r| r.image.file_name().is_some() && r.image.file_name() == image.file_name());
+    match (same_name.next(), same_name.next()) {
+        (Some(record), None) => Ok(record),
+        (Some(_), Some(_)) => Err(anyhow!(
+            "multiple images named `{}` are recorded in `{}`",
+            image.display(),
+            index_path.display()
+        )),
+        (None, _) => Err(anyhow!(
+            "image `{}` is not recorded in `{}`",
+            image.display(),
+            index_path.display()
+        )),
+    }
+}
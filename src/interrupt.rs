@@ -0,0 +1,164 @@
+//! Tears down the spawned child processes when the process is interrupted.
+//!
+//! All children that are spawned through [`spawn`] are recorded until they are dropped. On
+//! SIGINT or SIGTERM (Ctrl-C or Ctrl-Break on Windows), they are terminated and all later
+//! waits return, so that the callers can return an `Interrupted` error.
+
+use std::{
+    io,
+    ops::{Deref, DerefMut},
+    process::{Child, Command},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, Once,
+    },
+    thread,
+    time::Duration,
+};
+
+/// How long the children get for exiting after SIGTERM before they are killed.
+const KILL_DELAY: Duration = Duration::from_secs(2);
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static CHILDREN: Mutex<Vec<RegisteredChild>> = Mutex::new(Vec::new());
+static INSTALL_HANDLER: Once = Once::new();
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct RegisteredChild {
+    id: u32,
+    process_group: bool,
+}
+
+/// Returns whether a SIGINT or SIGTERM was received.
+pub(crate) fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Spawns the given command and records the child so that it is terminated on interrupt.
+///
+/// If `process_group` is set, the child is started in a new process group on Unix, so that
+/// its own children (e.g. the `rustc` processes of `cargo build`) are terminated too. Since
+/// processes in a background process group are stopped when they read from the terminal,
+/// this should only be used for commands without terminal input.
+pub(crate) fn spawn(command: &mut Command, process_group: bool) -> io::Result<ChildGuard> {
+    INSTALL_HANDLER.call_once(|| {
+        // fails if the application already installed a handler, which takes precedence
+        let _ = ctrlc::set_handler(handle_interrupt);
+    });
+
+    #[cfg(unix)]
+    {
+        if process_group {
+            use std::os::unix::process::CommandExt;
+            command.process_group(0);
+        }
+    }
+
+    let mut children = CHILDREN.lock().expect("children mutex poisoned");
+    if interrupted() {
+        return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+    }
+    let child = command.spawn()?;
+    let registered = RegisteredChild {
+        id: child.id(),
+        process_group,
+    };
+    children.push(registered);
+    Ok(ChildGuard { child, registered })
+}
+
+/// A spawned child process that is terminated on interrupt.
+///
+/// The child is no longer terminated after the guard is dropped.
+pub(crate) struct ChildGuard {
+    child: Child,
+    registered: RegisteredChild,
+}
+
+impl ChildGuard {
+    /// Forcibly kills the child and, if it was started in its own process group, all
+    /// other processes in the group.
+    ///
+    /// Killing the whole group ensures that no process keeps the output pipes of the child
+    /// open, e.g. when QEMU is started through a wrapper script.
+    pub(crate) fn kill(&mut self) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            if self.registered.process_group {
+                signal_child(&self.registered, true);
+                return Ok(());
+            }
+        }
+        self.child.kill()
+    }
+}
+
+impl Deref for ChildGuard {
+    type Target = Child;
+
+    fn deref(&self) -> &Child {
+        &self.child
+    }
+}
+
+impl DerefMut for ChildGuard {
+    fn deref_mut(&mut self) -> &mut Child {
+        &mut self.child
+    }
+}
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        let mut children = CHILDREN.lock().expect("children mutex poisoned");
+        children.retain(|c| *c != self.registered);
+    }
+}
+
+fn handle_interrupt() {
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        // a second interrupt while we are still tearing down the children
+        std::process::exit(130);
+    }
+    if CHILDREN.lock().expect("children mutex poisoned").is_empty() {
+        // nothing to tear down, so exit like without a handler
+        std::process::exit(130);
+    }
+    signal_children(false);
+    thread::sleep(KILL_DELAY);
+    signal_children(true);
+}
+
+/// Sends SIGTERM (or SIGKILL if `force` is set) to all recorded children.
+///
+/// On Windows, the children are attached to the same console, so they receive the Ctrl-C
+/// event themselves. Thus, only the forced kill is needed there.
+fn signal_children(force: bool) {
+    let children = CHILDREN.lock().expect("children mutex poisoned");
+    for child in children.iter() {
+        signal_child(child, force);
+    }
+}
+
+fn signal_child(child: &RegisteredChild, force: bool) {
+    #[cfg(unix)]
+    {
+        let pid = child.id as libc::pid_t;
+        let target = if child.process_group { -pid } else { pid };
+        let signal = if force { libc::SIGKILL } else { libc::SIGTERM };
+        // SAFETY: `kill` has no memory safety requirements. The guards are dropped
+        // right after the children are reaped, which makes pid reuse in between very
+        // unlikely.
+        unsafe {
+            libc::kill(target, signal);
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        if force {
+            let _ = Command::new("taskkill")
+                .args(["/F", "/T", "/PID"])
+                .arg(child.id.to_string())
+                .output();
+        }
+    }
+}
@@ -7,7 +7,12 @@
 pub mod args;
 pub mod builder;
 pub mod config;
+pub mod images;
+pub mod plan;
 pub mod run;
+pub mod setup;
+
+mod interrupt;
 
 /// Contains help messages for the command line application.
 pub mod help;
@@ -1,12 +1,41 @@
 /// Executable for `bootimage runner`.
 use anyhow::{anyhow, Context, Result};
 use bootimage::{
-    args::{RunnerArgs, RunnerCommand},
-    builder::Builder,
-    config, help, run,
+    args::{RunnerArgs, RunnerCommand, SetupArgs, SetupCommand, TestArgs, TestCommand, TestFormat},
+    builder::{self, Builder},
+    config::{self, ImageNameVars},
+    help, images,
+    plan::{Plan, PlanFormat},
+    run::{self, RunError, TestReport, TestStatus},
+    setup,
 };
 use std::process;
-use std::{env, path::Path};
+use std::{
+    collections::VecDeque,
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Mutex,
+    },
+    thread,
+    time::Instant,
+};
+
+/// The cargo arguments that select the targets to build, in which case `--tests` is not
+/// added by `bootimage test`.
+const TARGET_SELECTION_ARGS: &[&str] = &[
+    "--lib",
+    "--bin",
+    "--bins",
+    "--test",
+    "--tests",
+    "--bench",
+    "--benches",
+    "--example",
+    "--examples",
+    "--all-targets",
+];
 
 pub fn main() -> Result<()> {
     let mut raw_args = env::args();
@@ -23,15 +52,50 @@ pub fn main() -> Result<()> {
             file_stem
         ));
     }
-    match raw_args.next().as_deref() {
-        Some("runner") => {},
+    let exit_code = match raw_args.next().as_deref() {
+        Some("runner") => match RunnerCommand::parse_args(raw_args)? {
+            RunnerCommand::Runner(args) => Some(runner(args)?),
+            RunnerCommand::Version => {
+                help::print_version();
+                None
+            }
+            RunnerCommand::Help => {
+                help::print_runner_help();
+                None
+            }
+        },
+        Some("setup") => match SetupCommand::parse_args(raw_args)? {
+            SetupCommand::Setup(args) => {
+                setup(args)?;
+                None
+            }
+            SetupCommand::Version => {
+                help::print_version();
+                None
+            }
+            SetupCommand::Help => {
+                help::print_setup_help();
+                None
+            }
+        },
+        Some("test") => match TestCommand::parse_args(raw_args)? {
+            TestCommand::Test(args) => Some(test(args)?),
+            TestCommand::Version => {
+                help::print_version();
+                None
+            }
+            TestCommand::Help => {
+                help::print_test_help();
+                None
+            }
+        },
         Some("--help") | Some("-h") => {
             help::print_help();
-            return Ok(())
+            None
         }
         Some("--version") => {
             help::print_version();
-            return Ok(())
+            None
         }
         Some(other) => return Err(anyhow!(
             "Unsupported subcommand `{:?}`. See `bootimage --help` for an overview of supported subcommands.", other
@@ -39,18 +103,6 @@ pub fn main() -> Result<()> {
         None => return Err(anyhow!(
             "Please invoke bootimage with a subcommand. See `bootimage --help` for more information."
         )),
-    }
-
-    let exit_code = match RunnerCommand::parse_args(raw_args)? {
-        RunnerCommand::Runner(args) => Some(runner(args)?),
-        RunnerCommand::Version => {
-            help::print_version();
-            None
-        }
-        RunnerCommand::Help => {
-            help::print_runner_help();
-            None
-        }
     };
 
     if let Some(code) = exit_code {
@@ -60,9 +112,18 @@ pub fn main() -> Result<()> {
     Ok(())
 }
 
-pub(crate) fn runner(args: RunnerArgs) -> Result<i32> {
+pub(crate) fn runner(mut args: RunnerArgs) -> Result<i32> {
+    if let Some(index_path) = args.images.clone() {
+        return run_recorded_image(args, &index_path);
+    }
+
     let mut builder = Builder::new(None)?;
-    let config = config::read_config(builder.manifest_path())?;
+    let profile = builder::profile_from_executable_path(&args.executable);
+    let mut config = config::read_config(builder.manifest_path())?;
+    builder.set_log_retention(config.log_retention);
+    if let Some(profile) = &profile {
+        config = config.for_profile(profile);
+    }
     let exe_parent = args
         .executable
         .parent()
@@ -74,15 +135,12 @@ pub(crate) fn runner(args: RunnerArgs) -> Result<i32> {
         .ok_or_else(|| anyhow!("kernel executable's parent file name is not valid UTF-8"))?
         .starts_with("rustdoctest");
     let is_test = is_doctest || exe_parent.ends_with("deps");
+    if is_test {
+        if let Some(test_name) = builder::test_name_from_executable_path(&args.executable) {
+            config = config.for_test(&test_name);
+        }
+    }
 
-    let bin_name = args
-        .executable
-        .file_stem()
-        .ok_or_else(|| anyhow!("kernel executable has no file stem"))?
-        .to_str()
-        .ok_or_else(|| anyhow!("kernel executable file stem is not valid UTF-8"))?;
-
-    let output_bin_path = exe_parent.join(format!("bootimage-{}.bin", bin_name));
     let executable_canonicalized = args.executable.canonicalize().with_context(|| {
         format!(
             "failed to canonicalize executable path `{}`",
@@ -98,14 +156,504 @@ pub(crate) fn runner(args: RunnerArgs) -> Result<i32> {
         .context("Failed to read CARGO_MANIFEST_DIR environment variable")?;
     let kernel_manifest_path = Path::new(&manifest_dir).join("Cargo.toml");
 
+    // Use the same `{bin}` name as `cargo bootimage` (e.g. `test-basic_boot` instead of
+    // the hashed file stem), so that the image paths are predictable. Executables without
+    // a matching cargo target (e.g. doctests) use their file stem.
+    let kernel_executable = builder
+        .kernel_executable_from_path(&kernel_manifest_path, &executable_canonicalized)
+        .context("Failed to run cargo metadata to find the target of the kernel executable")?;
+    let bin_name = match &kernel_executable {
+        Some(kernel_executable) => kernel_executable.image_bin_name(),
+        None => args
+            .executable
+            .file_stem()
+            .ok_or_else(|| anyhow!("kernel executable has no file stem"))?
+            .to_str()
+            .ok_or_else(|| anyhow!("kernel executable file stem is not valid UTF-8"))?
+            .to_owned(),
+    };
+    builder.set_log_name(&bin_name);
+
+    // Doctests are placed in unique temporary directories, but their executables
+    // all have the same name. To avoid collisions, we always put their disk images
+    // next to the executable.
+    if is_doctest {
+        config.out_dir = None;
+    }
+    let target_dir = builder
+        .target_dir()
+        .context("Failed to run cargo metadata to find out target directory")?;
+    let image_name_vars = ImageNameVars {
+        package: env::var("CARGO_PKG_NAME")
+            .context("Failed to read CARGO_PKG_NAME environment variable")?,
+        bin: bin_name,
+        version: env::var("CARGO_PKG_VERSION")
+            .context("Failed to read CARGO_PKG_VERSION environment variable")?,
+        target: builder::target_from_executable_path(&executable_canonicalized, target_dir)
+            .unwrap_or_else(|| "unknown".into()),
+        profile: profile.unwrap_or_else(|| "dev".into()),
+    };
+    let output_bin_path = config.image_path(&image_name_vars, exe_parent);
+
+    // The runner is invoked by cargo, so the `BOOTIMAGE_DRY_RUN` environment
+    // variable is the easiest way to request a dry run.
+    let dry_run = match (args.dry_run, env::var("BOOTIMAGE_DRY_RUN")) {
+        (Some(format), _) => Some(format),
+        (None, Ok(format)) if format == "1" => Some(PlanFormat::Text),
+        (None, Ok(format)) => Some(format.parse().context("Invalid BOOTIMAGE_DRY_RUN value")?),
+        (None, Err(_)) => None,
+    };
+    if let Some(format) = dry_run {
+        let mut plan = Plan::default();
+        plan.notes.push(
+            "the path of the bootloader executable is predicted, the actual path is only \
+             known after the build"
+                .into(),
+        );
+        plan.commands.extend(builder.plan_create_bootimage(
+            &kernel_manifest_path,
+            &executable_canonicalized,
+            &output_bin_path,
+            &config,
+        )?);
+        plan.notes.extend(config.notes.iter().cloned());
+        if let Some(notice) = run::accel_fallback_notice(&config) {
+            plan.notes.push(notice.into());
+        }
+        plan.commands
+            .push(run::plan(&config, &args, &output_bin_path, is_test));
+        plan.print(format);
+        return Ok(0);
+    }
+
+    apply_env_args(&mut args)?;
+    // keep stdout free for the JSON events
+    let quiet = args.quiet || (is_test && args.test_format == Some(TestFormat::Json));
+
+    if let Some(parent) = output_bin_path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create output directory `{}`", parent.display()))?;
+    }
+
     builder.create_bootimage(
         &kernel_manifest_path,
         &executable_canonicalized,
         &output_bin_path,
-        args.quiet,
+        &config,
+        quiet,
     )?;
 
-    let exit_code = run::run(config, args, &output_bin_path, is_test)?;
+    run_image(config, args, &output_bin_path, is_test, builder.log_dir())
+}
+
+/// Applies the `BOOTIMAGE_TEST_FORMAT` and `BOOTIMAGE_BLESS` environment variables to the
+/// runner arguments.
+fn apply_env_args(args: &mut RunnerArgs) -> Result<()> {
+    // Like the `--format` argument, the `BOOTIMAGE_TEST_FORMAT` environment variable
+    // can be used to request libtest JSON events, e.g. for IDEs.
+    if args.test_format.is_none() {
+        if let Ok(format) = env::var("BOOTIMAGE_TEST_FORMAT") {
+            args.test_format = Some(
+                format
+                    .parse()
+                    .context("Invalid BOOTIMAGE_TEST_FORMAT value")?,
+            );
+        }
+    }
+    if env::var("BOOTIMAGE_BLESS").as_deref() == Ok("1") {
+        args.bless = true;
+    }
+    Ok(())
+}
+
+/// Runs the given disk image and returns the exit code of the runner.
+fn run_image(
+    config: config::Config,
+    args: RunnerArgs,
+    image_path: &Path,
+    is_test: bool,
+    log_dir: Option<&Path>,
+) -> Result<i32> {
+    let exit_code = match run::run(config, args, image_path, is_test, log_dir) {
+        Err(err @ RunError::Interrupted) => {
+            // use the conventional exit code for SIGINT
+            eprintln!("Error: {}", err);
+            130
+        }
+        result => result?,
+    };
 
     Ok(exit_code)
 }
+
+/// Runs a disk image that was created by `cargo bootimage` with the kind and test settings
+/// that are recorded in the given `images.json` index.
+///
+/// The other settings, e.g. the run command and the exit codes of tests, are read from the
+/// configuration of the kernel package.
+fn run_recorded_image(mut args: RunnerArgs, index_path: &Path) -> Result<i32> {
+    let image_path = args.executable.clone();
+    let record = images::find_image(index_path, &image_path)?;
+    let manifest_path = match env::var("CARGO_MANIFEST_DIR") {
+        Ok(dir) => Path::new(&dir).join("Cargo.toml"),
+        // archived images might be run on a machine without the kernel sources
+        Err(_) if record.manifest_path.exists() => record.manifest_path.clone(),
+        Err(_) => locate_cargo_manifest::locate_manifest()
+            .context("Failed to locate the Cargo.toml of the kernel crate")?,
+    };
+    let mut config = config::read_config(&manifest_path)?.for_profile(&record.profile);
+    if record.test {
+        config = config.for_test(&record.target_name);
+        config.test_args = record.test_args.clone();
+        if let Some(timeout) = record.test_timeout {
+            config.test_timeout = timeout;
+        }
+    }
+    // the name of the test and the symbols for timeout diagnostics are taken from the
+    // kernel executable
+    args.executable = record.executable.clone();
+
+    if let Some(format) = args.dry_run {
+        let mut plan = Plan::default();
+        plan.notes.extend(config.notes.iter().cloned());
+        if let Some(notice) = run::accel_fallback_notice(&config) {
+            plan.notes.push(notice.into());
+        }
+        plan.commands
+            .push(run::plan(&config, &args, &image_path, record.test));
+        plan.print(format);
+        return Ok(0);
+    }
+
+    apply_env_args(&mut args)?;
+    run_image(config, args, &image_path, record.test, None)
+}
+
+pub(crate) fn setup(args: SetupArgs) -> Result<()> {
+    let manifest_path = match args.manifest_path {
+        Some(path) => path,
+        None => locate_cargo_manifest::locate_manifest()
+            .context("Failed to locate the Cargo.toml of the kernel crate")?,
+    };
+
+    let modified = setup::setup(&manifest_path, args.target.as_deref())?;
+    if !args.quiet {
+        if modified.is_empty() {
+            println!("Project at `{}` is already set up", manifest_path.display());
+        }
+        for path in modified {
+            println!("Updated `{}`", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// A test executable whose disk image was created by `bootimage test`.
+struct TestJob {
+    /// The name of the test executable, e.g. `test-should_panic`
+    name: String,
+    config: config::Config,
+    args: RunnerArgs,
+    image_path: PathBuf,
+    log_dir: Option<PathBuf>,
+}
+
+pub(crate) fn test(args: TestArgs) -> Result<i32> {
+    let manifest_path = match args.manifest_path() {
+        Some(path) => path.to_owned(),
+        None => locate_cargo_manifest::locate_manifest()
+            .context("Failed to locate the Cargo.toml of the kernel crate")?,
+    };
+    let mut builder = Builder::new(Some(manifest_path))?;
+    let config = config::read_config(builder.manifest_path())?;
+    builder.set_log_retention(config.log_retention);
+    let quiet = args.quiet();
+
+    // `cargo test --no-run` builds the test executables without running them
+    let mut build_config = config.clone();
+    if build_config.build_command == ["build"] {
+        build_config.build_command = vec!["test".into(), "--no-run".into()];
+    }
+    let mut cargo_args = args.cargo_args().to_vec();
+    let selects_targets = cargo_args.iter().any(|arg| {
+        let flag = arg.split('=').next().unwrap_or_default();
+        TARGET_SELECTION_ARGS.contains(&flag)
+    });
+    if !selects_targets {
+        cargo_args.insert(0, "--tests".into());
+    }
+    let executables = builder.build_kernel(&cargo_args, &build_config, quiet)?;
+
+    let target_dir = builder
+        .target_dir()
+        .context("Failed to run cargo metadata to find out target directory")?
+        .to_owned();
+    let bless = env::var("BOOTIMAGE_BLESS").as_deref() == Ok("1");
+    let mut jobs = VecDeque::new();
+    let mut filtered_out = 0;
+    // integration tests with `harness = false` are built without the test harness, but are
+    // test executables too (unlike the binaries that cargo builds for integration tests)
+    let test_executables = executables
+        .into_iter()
+        .filter(|e| e.test || e.target_kind == "test");
+    for executable in test_executables {
+        let name = executable.image_bin_name();
+        if !args.matches(&name) {
+            filtered_out += 1;
+            continue;
+        }
+        let kernel_package = builder
+            .kernel_package(&executable)
+            .context("Failed to run cargo metadata to find out kernel manifest path")?
+            .ok_or_else(|| {
+                anyhow!(
+                    "Failed to find package `{}` in cargo metadata output",
+                    executable.package_id
+                )
+            })?
+            .clone();
+        let profile = args
+            .profile()
+            .map(String::from)
+            .or_else(|| builder::profile_from_executable_path(&executable.path))
+            .unwrap_or_else(|| "dev".into());
+        let kernel_config = config::read_config(&kernel_package.manifest_path)?
+            .for_profile(&profile)
+            .for_test(&executable.target_name);
+        if !builder::has_bootloader_dependency(&kernel_package)
+            && kernel_config.bootloader_prebuilt.is_none()
+        {
+            if !quiet {
+                println!(
+                    "Skipping {} `{}` of package `{}` (no bootloader dependency)",
+                    executable.target_kind, executable.target_name, kernel_package.name
+                );
+            }
+            continue;
+        }
+
+        let out_dir = executable
+            .path
+            .parent()
+            .ok_or_else(|| anyhow!("executable has no parent path"))?;
+        let image_name_vars = ImageNameVars {
+            package: kernel_package.name.clone(),
+            bin: name.clone(),
+            version: kernel_package.version.to_string(),
+            target: builder::target_from_executable_path(&executable.path, &target_dir)
+                .unwrap_or_else(|| "unknown".into()),
+            profile,
+        };
+        let image_path = kernel_config.image_path(&image_name_vars, out_dir);
+        if let Some(parent) = image_path.parent() {
+            fs::create_dir_all(parent).with_context(|| {
+                format!("Failed to create output directory `{}`", parent.display())
+            })?;
+        }
+        builder.create_bootimage(
+            &kernel_package.manifest_path,
+            &executable.path,
+            &image_path,
+            &kernel_config,
+            quiet,
+        )?;
+
+        // the serial logs of the test executables are kept apart in the shared log directory
+        let log_dir = match builder.log_dir() {
+            Some(log_dir) => {
+                let log_dir = log_dir.join(&name);
+                fs::create_dir_all(&log_dir).with_context(|| {
+                    format!("Failed to create log directory `{}`", log_dir.display())
+                })?;
+                Some(log_dir)
+            }
+            None => None,
+        };
+        let runner_args = RunnerArgs {
+            executable: executable.path,
+            quiet: true,
+            dry_run: None,
+            junit: args.junit().map(PathBuf::from),
+            test_format: None,
+            bless,
+            retries: args.retries(),
+            images: None,
+            runner_args: None,
+        };
+        jobs.push_back(TestJob {
+            name,
+            config: kernel_config,
+            args: runner_args,
+            image_path,
+            log_dir,
+        });
+    }
+    if jobs.is_empty() && filtered_out == 0 {
+        return Err(anyhow!(
+            "no test executables with a bootloader dependency built"
+        ));
+    }
+
+    let parallel_jobs = args
+        .jobs()
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+        .min(jobs.len())
+        .max(1);
+    let total = jobs.len();
+    println!();
+    println!(
+        "running {} test executable(s) in {} parallel job(s)",
+        total, parallel_jobs
+    );
+
+    let start = Instant::now();
+    let queue = Mutex::new(jobs);
+    let stop = AtomicBool::new(false);
+    let (sender, receiver) = mpsc::channel();
+    let mut results = Vec::new();
+    let mut interrupted = false;
+    thread::scope(|scope| {
+        for _ in 0..parallel_jobs {
+            let sender = sender.clone();
+            let (queue, stop) = (&queue, &stop);
+            scope.spawn(move || loop {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let job = match queue.lock().expect("queue mutex poisoned").pop_front() {
+                    Some(job) => job,
+                    None => break,
+                };
+                let TestJob {
+                    name,
+                    config,
+                    args,
+                    image_path,
+                    log_dir,
+                } = job;
+                let result = run::run_test(config, args, &image_path, log_dir.as_deref());
+                if sender.send((name, result)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        for (name, result) in receiver {
+            let report = match result {
+                Ok(report) => report,
+                Err(RunError::Interrupted) => {
+                    interrupted = true;
+                    stop.store(true, Ordering::SeqCst);
+                    continue;
+                }
+                Err(err) => TestReport::failed(format!("Error: {}", err)),
+            };
+            print_test_result(&name, &report);
+            if args.fail_fast() && matches!(report.status, TestStatus::Failed(_)) {
+                stop.store(true, Ordering::SeqCst);
+            }
+            results.push((name, report));
+        }
+    });
+    if interrupted {
+        eprintln!("Error: {}", RunError::Interrupted);
+        return Ok(130);
+    }
+
+    let not_run = total - results.len();
+    let success = print_test_summary(&mut results, filtered_out, not_run, start.elapsed());
+    Ok(if success { 0 } else { 1 })
+}
+
+/// Prints the result line of a finished test executable.
+fn print_test_result(name: &str, report: &TestReport) {
+    let status = match &report.status {
+        TestStatus::Passed => "ok".to_owned(),
+        TestStatus::Skipped => "skipped".to_owned(),
+        TestStatus::Flaky => format!("ok (flaky, {} attempts)", report.attempts),
+        TestStatus::Failed(_) => "FAILED".to_owned(),
+        _ => "unknown".to_owned(),
+    };
+    println!(
+        "test {} ... {} ({:.2}s)",
+        name,
+        status,
+        report.duration.as_secs_f64()
+    );
+}
+
+/// Prints the output of the failed test executables and the aggregated results.
+///
+/// Returns whether all test executables passed (or were skipped).
+fn print_test_summary(
+    results: &mut [(String, TestReport)],
+    filtered_out: usize,
+    not_run: usize,
+    duration: std::time::Duration,
+) -> bool {
+    results.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let count = |f: fn(&TestStatus) -> bool| results.iter().filter(|(_, r)| f(&r.status)).count();
+    let failed: Vec<_> = results
+        .iter()
+        .filter(|(_, r)| matches!(r.status, TestStatus::Failed(_)))
+        .collect();
+
+    println!();
+    if !failed.is_empty() {
+        println!("failures:");
+        println!();
+        for (name, report) in &failed {
+            println!("---- {} output ----", name);
+            for line in &report.output {
+                println!("{}", line);
+            }
+            if let TestStatus::Failed(failure) = &report.status {
+                println!("failure: {}", failure);
+            }
+            if !report.failed_cases.is_empty() {
+                println!("failed test cases: {}", report.failed_cases.join(", "));
+            }
+            println!();
+        }
+        println!("failures:");
+        for (name, _) in &failed {
+            println!("    {}", name);
+        }
+        println!();
+    }
+    let flaky: Vec<_> = results
+        .iter()
+        .filter(|(_, r)| r.status == TestStatus::Flaky)
+        .collect();
+    if !flaky.is_empty() {
+        println!("flaky:");
+        for (name, report) in &flaky {
+            println!("    {} ({} attempts)", name, report.attempts);
+        }
+        println!();
+    }
+
+    let case_count = |f: fn(&TestReport) -> usize| results.iter().map(|(_, r)| f(r)).sum::<usize>();
+    let success = failed.is_empty() && not_run == 0;
+    println!(
+        "test result: {}. {} passed; {} failed; {} skipped; {} flaky; {} filtered out; {} not \
+         run; finished in {:.2}s",
+        if success { "ok" } else { "FAILED" },
+        count(|s| *s == TestStatus::Passed),
+        failed.len(),
+        count(|s| *s == TestStatus::Skipped),
+        flaky.len(),
+        filtered_out,
+        not_run,
+        duration.as_secs_f64()
+    );
+    println!(
+        "test cases: {} passed; {} failed; {} ignored",
+        case_count(|r| r.passed),
+        case_count(|r| r.failed),
+        case_count(|r| r.ignored)
+    );
+    println!();
+    success
+}
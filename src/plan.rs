@@ -0,0 +1,155 @@
+//! Describes the commands that are executed by `cargo bootimage` and `bootimage runner`.
+//!
+//! Used for the `--dry-run` mode, which prints the commands instead of executing them.
+
+use anyhow::{anyhow, Error};
+use json::JsonValue;
+use std::{env, path::PathBuf, process::Command, str::FromStr};
+
+/// A description of a command that would be executed.
+#[derive(Debug, Clone)]
+pub struct CommandDescription {
+    /// The build phase that the command belongs to, e.g. `bootloader build`.
+    pub phase: String,
+    /// The program that is executed.
+    pub program: String,
+    /// The arguments that are passed to the program.
+    pub args: Vec<String>,
+    /// Whether the environment of the current process is cleared before applying `env`.
+    pub env_clear: bool,
+    /// Environment variables that are set (`Some`) or removed (`None`) for the command.
+    pub env: Vec<(String, Option<String>)>,
+    /// The working directory of the command.
+    pub current_dir: PathBuf,
+}
+
+impl CommandDescription {
+    /// Creates a description of the given command.
+    ///
+    /// Since `Command` does not expose whether `env_clear` was called, this needs to be
+    /// specified separately through the `env_clear` argument.
+    pub fn new(phase: &str, command: &Command, env_clear: bool) -> Self {
+        let lossy = |s: &std::ffi::OsStr| s.to_string_lossy().into_owned();
+        CommandDescription {
+            phase: phase.to_owned(),
+            program: lossy(command.get_program()),
+            args: command.get_args().map(lossy).collect(),
+            env_clear,
+            env: command
+                .get_envs()
+                .map(|(key, value)| (lossy(key), value.map(lossy)))
+                .collect(),
+            current_dir: command
+                .get_current_dir()
+                .map(PathBuf::from)
+                .or_else(|| env::current_dir().ok())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Returns a human-readable description of the command.
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{}:\n", self.phase);
+        text += &format!("    working directory: {}\n", self.current_dir.display());
+        if self.env_clear {
+            text += "    environment: cleared\n";
+        }
+        for (key, value) in &self.env {
+            match value {
+                Some(value) => text += &format!("    env: {}={}\n", key, quote(value)),
+                None => text += &format!("    env: {} (removed)\n", key),
+            }
+        }
+        let mut command_line = vec![quote(&self.program)];
+        command_line.extend(self.args.iter().map(|arg| quote(arg)));
+        text += &format!("    command: {}\n", command_line.join(" "));
+        text
+    }
+
+    /// Returns a JSON description of the command.
+    pub fn to_json(&self) -> JsonValue {
+        let mut env = JsonValue::new_object();
+        for (key, value) in &self.env {
+            env[key.as_str()] = match value {
+                Some(value) => value.as_str().into(),
+                None => JsonValue::Null,
+            };
+        }
+        json::object! {
+            "phase" => self.phase.as_str(),
+            "program" => self.program.as_str(),
+            "args" => self.args.clone(),
+            "env_clear" => self.env_clear,
+            "env" => env,
+            "current_dir" => self.current_dir.display().to_string(),
+        }
+    }
+}
+
+/// Quotes the given string if it is empty or contains whitespace or quotes.
+fn quote(s: &str) -> String {
+    if s.is_empty() || s.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'') {
+        format!("{:?}", s)
+    } else {
+        s.to_owned()
+    }
+}
+
+/// The output format of a dry run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanFormat {
+    /// Human-readable text
+    Text,
+    /// A JSON object with a `commands` array
+    Json,
+}
+
+impl FromStr for PlanFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(PlanFormat::Text),
+            "json" => Ok(PlanFormat::Json),
+            other => Err(anyhow!(
+                "invalid dry run format `{}` (expected `text` or `json`)",
+                other
+            )),
+        }
+    }
+}
+
+/// The list of commands that would be executed by a dry run.
+#[derive(Debug, Clone, Default)]
+pub struct Plan {
+    /// The commands in the order in which they would be executed.
+    pub commands: Vec<CommandDescription>,
+    /// Additional remarks, e.g. about placeholders in the commands.
+    pub notes: Vec<String>,
+}
+
+impl Plan {
+    /// Prints the plan to stdout in the given format.
+    pub fn print(&self, format: PlanFormat) {
+        match format {
+            PlanFormat::Text => {
+                for note in &self.notes {
+                    println!("note: {}", note);
+                }
+                for command in &self.commands {
+                    println!();
+                    print!("{}", command.to_text());
+                }
+            }
+            PlanFormat::Json => {
+                let plan = json::object! {
+                    "notes" => self.notes.clone(),
+                    "commands" => JsonValue::Array(
+                        self.commands.iter().map(CommandDescription::to_json).collect()
+                    ),
+                };
+                println!("{}", plan.pretty(4));
+            }
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! Captures the state of QEMU before a timed out test executable is killed.
+//!
+//! Test executables are started with a QMP socket in the temporary directory. When a test
+//! times out, the runner connects to it and queries `info registers` and `info cpus` through
+//! the human monitor. The instruction pointer is resolved to a function symbol of the kernel
+//! executable, so that the timeout error shows where the kernel was stuck.
+
+use std::{
+    env, fmt, fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use xmas_elf::{
+    sections::SectionData,
+    symbol_table::{Entry, Type},
+    ElfFile,
+};
+
+/// Distinguishes the sockets of test executables that are run in parallel.
+static SOCKET_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// The state of QEMU when a test executable timed out.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct TimeoutDiagnostics {
+    /// The instruction pointer of the first CPU, parsed from `info registers`
+    pub rip: Option<u64>,
+    /// The function symbol of the kernel executable that contains `rip`, with the offset into it
+    pub symbol: Option<String>,
+    /// The output of the `info registers` monitor command
+    pub registers: String,
+    /// The output of the `info cpus` monitor command
+    pub cpus: String,
+}
+
+impl TimeoutDiagnostics {
+    /// Returns a short description of where the kernel was stuck, e.g.
+    /// `RIP 0x201234 (kernel::spin+0x4)`.
+    pub fn location(&self) -> Option<String> {
+        let rip = self.rip?;
+        Some(match &self.symbol {
+            Some(symbol) => format!("RIP {:#x} ({})", rip, symbol),
+            None => format!("RIP {:#x}", rip),
+        })
+    }
+}
+
+impl fmt::Display for TimeoutDiagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(location) = self.location() {
+            writeln!(f, "{}\n", location)?;
+        }
+        writeln!(f, "info cpus:\n{}\n", self.cpus)?;
+        writeln!(f, "info registers:\n{}", self.registers)
+    }
+}
+
+/// A QMP socket that QEMU listens on.
+///
+/// The socket file is removed when the value is dropped.
+pub(crate) struct QmpSocket {
+    path: PathBuf,
+}
+
+impl QmpSocket {
+    /// Creates a new socket path in the temporary directory.
+    ///
+    /// Returns `None` on platforms without Unix domain sockets.
+    pub fn new() -> Option<Self> {
+        if !cfg!(unix) {
+            return None;
+        }
+        let path = env::temp_dir().join(format!(
+            "bootimage-{}-{}.qmp",
+            process::id(),
+            SOCKET_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        Some(QmpSocket { path })
+    }
+
+    /// The QEMU arguments that create the socket.
+    ///
+    /// QEMU doesn't wait for a connection, so the socket has no effect until a test times out.
+    pub fn qemu_args(&self) -> Vec<String> {
+        vec![
+            "-qmp".into(),
+            format!("unix:{},server=on,wait=off", self.path.display()),
+        ]
+    }
+
+    /// Queries the state of the running QEMU instance and resolves the instruction pointer
+    /// through the symbols of the given kernel executable.
+    pub fn capture(&self, kernel: &Path) -> io::Result<TimeoutDiagnostics> {
+        let (registers, cpus) = qmp::query(&self.path)?;
+        let rip = parse_rip(&registers);
+        let symbol = rip.and_then(|rip| symbolize(kernel, rip));
+        Ok(TimeoutDiagnostics {
+            rip,
+            symbol,
+            registers,
+            cpus,
+        })
+    }
+}
+
+impl Drop for QmpSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+mod qmp {
+    use json::JsonValue;
+    use std::{
+        io::{self, BufRead, BufReader, ErrorKind, Write},
+        os::unix::net::UnixStream,
+        path::Path,
+        time::Duration,
+    };
+
+    /// How long to wait for a response of QEMU before giving up.
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Returns the output of `info registers` and `info cpus`.
+    pub fn query(path: &Path) -> io::Result<(String, String)> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+
+        // the greeting of QEMU, which contains the version and capabilities
+        read_message(&mut reader)?;
+        execute(
+            &mut reader,
+            &mut writer,
+            json::object! { "execute" => "qmp_capabilities" },
+        )?;
+        let registers = monitor_command(&mut reader, &mut writer, "info registers")?;
+        let cpus = monitor_command(&mut reader, &mut writer, "info cpus")?;
+        Ok((registers, cpus))
+    }
+
+    /// Runs a command of the human monitor and returns its output.
+    fn monitor_command(
+        reader: &mut impl BufRead,
+        writer: &mut impl Write,
+        command: &str,
+    ) -> io::Result<String> {
+        let command = json::object! {
+            "execute" => "human-monitor-command",
+            "arguments" => json::object! { "command-line" => command },
+        };
+        let output = execute(reader, writer, command)?;
+        let output = output.as_str().unwrap_or_default();
+        Ok(output.replace("\r\n", "\n").trim_end().to_owned())
+    }
+
+    /// Sends the given command and returns the `return` value of the response.
+    fn execute(
+        reader: &mut impl BufRead,
+        writer: &mut impl Write,
+        command: JsonValue,
+    ) -> io::Result<JsonValue> {
+        writeln!(writer, "{}", command.dump())?;
+        loop {
+            let mut message = read_message(reader)?;
+            if message.has_key("return") {
+                return Ok(message["return"].take());
+            }
+            if message.has_key("error") {
+                return Err(io::Error::other(format!(
+                    "QMP command failed: {}",
+                    message["error"]["desc"]
+                )));
+            }
+            // skip asynchronous events
+        }
+    }
+
+    fn read_message(reader: &mut impl BufRead) -> io::Result<JsonValue> {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "QMP connection was closed",
+            ));
+        }
+        json::parse(&line).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+    }
+}
+
+#[cfg(not(unix))]
+mod qmp {
+    use std::{io, path::Path};
+
+    pub fn query(_path: &Path) -> io::Result<(String, String)> {
+        Err(io::Error::other("QMP sockets are only supported on Unix"))
+    }
+}
+
+/// Parses the instruction pointer of the first CPU from the output of `info registers`.
+fn parse_rip(registers: &str) -> Option<u64> {
+    let start = registers.find("RIP=")? + "RIP=".len();
+    let hex: String = registers[start..]
+        .chars()
+        .take_while(char::is_ascii_hexdigit)
+        .collect();
+    u64::from_str_radix(&hex, 16).ok()
+}
+
+/// Returns the demangled name of the function symbol that contains `address`, followed by
+/// the offset into the function (e.g. `kernel::spin+0x4`).
+///
+/// Returns `None` if the kernel executable has no symbol table or no function contains the
+/// address.
+fn symbolize(kernel: &Path, address: u64) -> Option<String> {
+    let data = fs::read(kernel).ok()?;
+    let elf = ElfFile::new(&data).ok()?;
+    let symbol_table = elf.find_section_by_name(".symtab")?;
+    let symbols = match symbol_table.get_data(&elf).ok()? {
+        SectionData::SymbolTable64(symbols) => symbols,
+        _ => return None,
+    };
+    let symbol = symbols
+        .iter()
+        // undefined symbols (section index 0) are imports without an address
+        .filter(|symbol| symbol.get_type() == Ok(Type::Func) && symbol.shndx() != 0)
+        .filter(|symbol| {
+            // treat symbols without a size as one byte, so that an exact match is found
+            let end = symbol.value().saturating_add(symbol.size().max(1));
+            symbol.value() <= address && address < end
+        })
+        .max_by_key(|symbol| symbol.value())?;
+    let name = symbol.get_name(&elf).ok()?;
+    Some(format!(
+        "{:#}+{:#x}",
+        rustc_demangle::demangle(name),
+        address - symbol.value()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::hint::black_box;
+
+    /// The `info registers` output of QEMU for a kernel that spins in long mode.
+    const REGISTERS: &str = "\
+RAX=0000000000000000 RBX=0000000000000000 RCX=00000000c0000080 RDX=0000000000000000
+RSI=0000000000000000 RDI=0000000000000000 RBP=0000000000000000 RSP=0000fc000000fe38
+R8 =0000000000000000 R9 =0000000000000000 R10=0000000000000000 R11=0000000000000000
+R12=0000000000000000 R13=0000000000000000 R14=0000000000000000 R15=0000000000000000
+RIP=000000000020117e RFL=00000002 [-------] CPL=0 II=0 A20=1 SMM=0 HLT=0
+ES =0010 0000000000000000 ffffffff 00cf9300 DPL=0 DS   [-WA]
+CS =0008 0000000000000000 ffffffff 00af9a00 DPL=0 CS64 [-R-]
+SS =0010 0000000000000000 ffffffff 00cf9300 DPL=0 DS   [-WA]
+DS =0010 0000000000000000 ffffffff 00cf9300 DPL=0 DS   [-WA]
+FS =0010 0000000000000000 ffffffff 00cf9300 DPL=0 DS   [-WA]
+GS =0010 0000000000000000 ffffffff 00cf9300 DPL=0 DS   [-WA]
+LDT=0000 0000000000000000 0000ffff 00008200 DPL=0 LDT
+TR =0000 0000000000000000 0000ffff 00008b00 DPL=0 TSS64-busy
+GDT=     0000000000007e50 00000027
+IDT=     0000000000000000 0000ffff
+CR0=80010011 CR2=0000000000000000 CR3=0000000000001000 CR4=00000020
+DR0=0000000000000000 DR1=0000000000000000 DR2=0000000000000000 DR3=0000000000000000
+DR6=00000000ffff0ff0 DR7=0000000000000400
+EFER=0000000000000d00";
+
+    #[test]
+    fn parse_rip_of_long_mode_registers() {
+        assert_eq!(parse_rip(REGISTERS), Some(0x20117e));
+    }
+
+    #[test]
+    fn parse_rip_without_rip() {
+        // in real mode, `info registers` shows the 32-bit EIP instead
+        assert_eq!(
+            parse_rip("EAX=00000000 EBX=00000000\nEIP=0000fff0 EFL=00000002"),
+            None
+        );
+        assert_eq!(parse_rip("RIP= RFL=00000002"), None);
+    }
+
+    #[inline(never)]
+    fn symbolize_target(value: u64) -> u64 {
+        black_box(value).wrapping_mul(3)
+    }
+
+    /// Returns the address of the given function symbol in the executable of the test.
+    fn symbol_address(executable: &Path, name: &str) -> u64 {
+        let data = fs::read(executable).unwrap();
+        let elf = ElfFile::new(&data).unwrap();
+        let symbols = match elf.find_section_by_name(".symtab").unwrap().get_data(&elf) {
+            Ok(SectionData::SymbolTable64(symbols)) => symbols,
+            _ => panic!("no 64-bit symbol table"),
+        };
+        symbols
+            .iter()
+            .find(|symbol| {
+                let demangled = symbol
+                    .get_name(&elf)
+                    .map(|n| format!("{:#}", rustc_demangle::demangle(n)));
+                demangled.as_deref() == Ok(name)
+            })
+            .unwrap_or_else(|| panic!("symbol `{}` not found", name))
+            .value()
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+    fn symbolize_test_executable() {
+        assert_eq!(symbolize_target(1), 3);
+        let executable = env::current_exe().unwrap();
+        let name = "bootimage::run::diagnostics::tests::symbolize_target";
+        let address = symbol_address(&executable, name);
+
+        assert_eq!(
+            symbolize(&executable, address),
+            Some(format!("{}+0x0", name))
+        );
+        assert_eq!(
+            symbolize(&executable, address + 2),
+            Some(format!("{}+0x2", name))
+        );
+        // no function contains the null address
+        assert_eq!(symbolize(&executable, 0), None);
+    }
+
+    #[test]
+    fn symbolize_without_elf_file() {
+        assert_eq!(symbolize(Path::new("does-not-exist"), 0x1000), None);
+        let not_elf = env::current_exe().unwrap().with_extension("not-elf");
+        fs::write(&not_elf, "no ELF file").unwrap();
+        assert_eq!(symbolize(&not_elf, 0x1000), None);
+        fs::remove_file(&not_elf).unwrap();
+    }
+
+    #[test]
+    fn location_includes_symbol() {
+        let diagnostics = TimeoutDiagnostics {
+            rip: parse_rip(REGISTERS),
+            symbol: Some("kernel::spin+0x4".into()),
+            registers: REGISTERS.into(),
+            cpus: "* CPU #0: thread_id=1234".into(),
+        };
+        assert_eq!(
+            diagnostics.location().as_deref(),
+            Some("RIP 0x20117e (kernel::spin+0x4)")
+        );
+        assert!(diagnostics
+            .to_string()
+            .starts_with("RIP 0x20117e (kernel::spin+0x4)\n\ninfo cpus:\n* CPU #0"));
+    }
+}
@@ -0,0 +1,531 @@
+//! Compares the serial output of test executables against files with the expected output.
+//!
+//! Each line of an expected output file is either a literal line, which has to match an
+//! output line exactly, or a regular expression prefixed with `re:`, which has to match a
+//! whole output line. Literal lines that start with `re:` or `\` can be escaped by
+//! prepending a `\`.
+
+use super::serial::SerialLine;
+use crate::config::ExpectedOutputConfig;
+use regex::Regex;
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// The number of unchanged lines shown around each change in a diff.
+const DIFF_CONTEXT: usize = 3;
+/// Diffs with more changes than this are shown as a full replacement to bound the memory
+/// usage of the diff algorithm.
+const MAX_DIFF_CHANGES: usize = 1000;
+
+/// The result of comparing the serial output against the expected output.
+#[derive(Debug)]
+pub(crate) enum Comparison {
+    /// The output matches the expected output
+    Matched,
+    /// The output doesn't match; contains a unified diff of the expected and actual output
+    Mismatch { path: PathBuf, diff: String },
+    /// The expected output file doesn't exist
+    Missing { path: PathBuf },
+    /// The expected output file was created or updated to the actual output
+    Blessed { path: PathBuf },
+}
+
+/// Compares the given serial output against the expected output file of the given test.
+///
+/// If `bless` is set, the expected output file is rewritten on a mismatch instead. Lines of
+/// the file that still match (e.g. regular expressions) are kept in this case.
+pub(crate) fn compare(
+    config: &ExpectedOutputConfig,
+    test: &str,
+    output: &[SerialLine],
+    bless: bool,
+) -> Result<Comparison, ExpectedOutputError> {
+    let path = config.path_for(test);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => Some(content),
+        Err(err) if err.kind() == ErrorKind::NotFound => None,
+        Err(error) => return Err(ExpectedOutputError::Io { path, error }),
+    };
+    let expected_lines: Vec<_> = content.as_deref().unwrap_or("").lines().collect();
+    let patterns = expected_lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| {
+            Pattern::parse(line).map_err(|error| ExpectedOutputError::InvalidRegex {
+                path: path.clone(),
+                line: i + 1,
+                error,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let actual: Vec<_> = output.iter().map(|l| l.text.as_str()).collect();
+
+    let matches = |i: usize, j: usize| patterns[i].matches(actual[j]);
+    let edits = if config.ignore_unmatched {
+        subsequence(patterns.len(), actual.len(), matches)
+    } else {
+        diff(patterns.len(), actual.len(), matches)
+    };
+    let mismatch = edits.iter().any(|edit| match edit {
+        Edit::Equal(..) => false,
+        Edit::Delete(_) => true,
+        Edit::Insert(_) => !config.ignore_unmatched,
+    });
+
+    if bless && (content.is_none() || mismatch) {
+        let mut blessed = String::new();
+        for edit in &edits {
+            match *edit {
+                Edit::Equal(i, _) => blessed.push_str(expected_lines[i]),
+                Edit::Insert(j) => blessed.push_str(&escape(actual[j])),
+                Edit::Delete(_) => continue,
+            }
+            blessed.push('\n');
+        }
+        let write = || -> io::Result<()> {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&path, blessed)
+        };
+        write().map_err(|error| ExpectedOutputError::Io {
+            path: path.clone(),
+            error,
+        })?;
+        return Ok(Comparison::Blessed { path });
+    }
+    if content.is_none() {
+        return Ok(Comparison::Missing { path });
+    }
+    if !mismatch {
+        return Ok(Comparison::Matched);
+    }
+    let diff = unified_diff(
+        &path,
+        &edits,
+        &expected_lines,
+        &actual,
+        config.ignore_unmatched,
+    );
+    Ok(Comparison::Mismatch { path, diff })
+}
+
+/// A line of an expected output file.
+enum Pattern {
+    Literal(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn parse(line: &str) -> Result<Self, regex::Error> {
+        if let Some(regex) = line.strip_prefix("re:") {
+            Regex::new(&format!("^(?:{})$", regex)).map(Pattern::Regex)
+        } else {
+            let literal = line.strip_prefix('\\').unwrap_or(line);
+            Ok(Pattern::Literal(literal.to_owned()))
+        }
+    }
+
+    fn matches(&self, line: &str) -> bool {
+        match self {
+            Pattern::Literal(literal) => literal == line,
+            Pattern::Regex(regex) => regex.is_match(line),
+        }
+    }
+}
+
+/// Escapes an output line so that it is interpreted as a literal line.
+fn escape(line: &str) -> String {
+    if line.starts_with("re:") || line.starts_with('\\') {
+        format!("\\{}", line)
+    } else {
+        line.to_owned()
+    }
+}
+
+/// An operation of an edit script that transforms the expected into the actual lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    /// The expected line with the first index matches the actual line with the second index
+    Equal(usize, usize),
+    /// The expected line with the given index is missing in the output
+    Delete(usize),
+    /// The actual line with the given index is not expected
+    Insert(usize),
+}
+
+/// Computes a shortest edit script between `n` expected and `m` actual lines using Myers'
+/// diff algorithm.
+fn diff(n: usize, m: usize, matches: impl Fn(usize, usize) -> bool) -> Vec<Edit> {
+    let (n_i, m_i) = (n as isize, m as isize);
+    let max = (n + m).min(MAX_DIFF_CHANGES) as isize;
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    let mut trace = Vec::new();
+
+    let mut found = false;
+    'outer: for d in 0..=max {
+        trace.push(v.clone());
+        for k in (-d..=d).step_by(2) {
+            let idx = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+                v[idx + 1]
+            } else {
+                v[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n_i && y < m_i && matches(x as usize, y as usize) {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n_i && y >= m_i {
+                found = true;
+                break 'outer;
+            }
+        }
+    }
+    if !found {
+        // too many changes, so show the output as a full replacement
+        let deletes = (0..n).map(Edit::Delete);
+        return deletes.chain((0..m).map(Edit::Insert)).collect();
+    }
+
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n_i, m_i);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let prev_k =
+            if k == -d || (k != d && v[(k - 1 + offset) as usize] < v[(k + 1 + offset) as usize]) {
+                k + 1
+            } else {
+                k - 1
+            };
+        let prev_x = v[(prev_k + offset) as usize];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            edits.push(Edit::Equal(x as usize - 1, y as usize - 1));
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            if x == prev_x {
+                edits.push(Edit::Insert(y as usize - 1));
+            } else {
+                edits.push(Edit::Delete(x as usize - 1));
+            }
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    edits.reverse();
+    edits
+}
+
+/// Computes an edit script that matches the `n` expected lines in order against the `m`
+/// actual lines, skipping over actual lines that don't match.
+///
+/// An expected line that can't be found is recorded as deleted and the search for the next
+/// expected line continues at the same position.
+fn subsequence(n: usize, m: usize, matches: impl Fn(usize, usize) -> bool) -> Vec<Edit> {
+    let mut edits = Vec::new();
+    let mut j = 0;
+    for i in 0..n {
+        match (j..m).find(|&j| matches(i, j)) {
+            Some(found) => {
+                edits.extend((j..found).map(Edit::Insert));
+                edits.push(Edit::Equal(i, found));
+                j = found + 1;
+            }
+            None => edits.push(Edit::Delete(i)),
+        }
+    }
+    edits.extend((j..m).map(Edit::Insert));
+    edits
+}
+
+/// Renders the given edit script as a unified diff.
+///
+/// If `ignore_unmatched` is set, only the missing expected lines start a hunk, since the
+/// additional output lines are allowed.
+fn unified_diff(
+    path: &Path,
+    edits: &[Edit],
+    expected: &[&str],
+    actual: &[&str],
+    ignore_unmatched: bool,
+) -> String {
+    let is_change = |edit: &Edit| match edit {
+        Edit::Equal(..) => false,
+        Edit::Delete(_) => true,
+        Edit::Insert(_) => !ignore_unmatched,
+    };
+    // the line numbers in the expected and actual output before each edit
+    let mut positions = Vec::with_capacity(edits.len() + 1);
+    let (mut old, mut new) = (0, 0);
+    for edit in edits {
+        positions.push((old, new));
+        match edit {
+            Edit::Equal(..) => {
+                old += 1;
+                new += 1;
+            }
+            Edit::Delete(_) => old += 1,
+            Edit::Insert(_) => new += 1,
+        }
+    }
+    positions.push((old, new));
+
+    // group the changes into hunks with overlapping context
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (i, _) in edits.iter().enumerate().filter(|(_, e)| is_change(e)) {
+        let start = i.saturating_sub(DIFF_CONTEXT);
+        let end = (i + 1 + DIFF_CONTEXT).min(edits.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut out = String::new();
+    let _ = writeln!(out, "--- {}", path.display());
+    let _ = writeln!(out, "+++ serial output");
+    for (start, end) in hunks {
+        let (old_start, new_start) = positions[start];
+        let (old_end, new_end) = positions[end];
+        let _ = writeln!(
+            out,
+            "@@ -{},{} +{},{} @@",
+            old_start + 1,
+            old_end - old_start,
+            new_start + 1,
+            new_end - new_start
+        );
+        for edit in &edits[start..end] {
+            let _ = match *edit {
+                Edit::Equal(_, j) => writeln!(out, " {}", actual[j]),
+                Edit::Delete(i) => writeln!(out, "-{}", expected[i]),
+                Edit::Insert(j) => writeln!(out, "+{}", actual[j]),
+            };
+        }
+    }
+    out
+}
+
+/// Reading or parsing an expected output file failed.
+#[derive(Debug, Error)]
+pub enum ExpectedOutputError {
+    /// Reading or writing the file failed
+    #[error("Failed to access expected output file `{}`: {error}", .path.display())]
+    Io {
+        /// The path of the expected output file
+        path: PathBuf,
+        /// The I/O error that occured
+        error: io::Error,
+    },
+
+    /// A `re:` line contains an invalid regular expression
+    #[error("Invalid regular expression in line {line} of `{}`: {error}", .path.display())]
+    InvalidRegex {
+        /// The path of the expected output file
+        path: PathBuf,
+        /// The line number of the regular expression
+        line: usize,
+        /// The error of the regex parser
+        error: regex::Error,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process, time::Duration};
+
+    /// Compares `output` against an expected output file with the given content (or no file)
+    /// and returns the comparison and the content of the file afterwards.
+    fn run_compare(
+        name: &str,
+        expected: Option<&str>,
+        output: &[&str],
+        ignore_unmatched: bool,
+        bless: bool,
+    ) -> (Comparison, Option<String>) {
+        let dir = env::temp_dir().join(format!(
+            "bootimage-expected-output-{}-{}",
+            process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("{test}.txt");
+        if let Some(expected) = expected {
+            fs::write(dir.join("test.txt"), expected).unwrap();
+        }
+        let config = ExpectedOutputConfig {
+            path: path.display().to_string(),
+            ignore_unmatched,
+        };
+        let output: Vec<_> = output
+            .iter()
+            .map(|text| SerialLine {
+                time: Duration::default(),
+                text: text.to_string(),
+            })
+            .collect();
+        let comparison = compare(&config, "test", &output, bless).unwrap();
+        let content = fs::read_to_string(dir.join("test.txt")).ok();
+        fs::remove_dir_all(&dir).unwrap();
+        (comparison, content)
+    }
+
+    fn mismatch_diff(comparison: Comparison) -> String {
+        match comparison {
+            Comparison::Mismatch { diff, .. } => diff,
+            other => panic!("expected a mismatch, got {:?}", other),
+        }
+    }
+
+    /// Strips the `---` line, which contains the temporary path.
+    fn hunks(diff: &str) -> String {
+        diff.lines()
+            .skip(1)
+            .map(|line| format!("{}\n", line))
+            .collect()
+    }
+
+    #[test]
+    fn equal_output_matches() {
+        let (comparison, _) = run_compare("equal", Some("a\nb\n"), &["a", "b"], false, false);
+        assert!(matches!(comparison, Comparison::Matched));
+    }
+
+    #[test]
+    fn additional_line_is_inserted() {
+        let (comparison, _) = run_compare("insert", Some("a\nc\n"), &["a", "b", "c"], false, false);
+        assert_eq!(
+            hunks(&mismatch_diff(comparison)),
+            "+++ serial output\n@@ -1,2 +1,3 @@\n a\n+b\n c\n"
+        );
+    }
+
+    #[test]
+    fn missing_line_is_deleted() {
+        let (comparison, _) = run_compare("delete", Some("a\nb\nc\n"), &["a", "c"], false, false);
+        assert_eq!(
+            hunks(&mismatch_diff(comparison)),
+            "+++ serial output\n@@ -1,3 +1,2 @@\n a\n-b\n c\n"
+        );
+    }
+
+    #[test]
+    fn diff_only_shows_context_around_changes() {
+        let expected = "1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+        let output = ["1", "2", "3", "4", "5", "6", "7", "8", "x"];
+        let (comparison, _) = run_compare("context", Some(expected), &output, false, false);
+        assert_eq!(
+            hunks(&mismatch_diff(comparison)),
+            "+++ serial output\n@@ -6,4 +6,4 @@\n 6\n 7\n 8\n-9\n+x\n"
+        );
+    }
+
+    #[test]
+    fn regex_lines_match_whole_lines() {
+        let expected = "re:booted in \\d+ms\n";
+        let (comparison, _) =
+            run_compare("regex", Some(expected), &["booted in 12ms"], false, false);
+        assert!(matches!(comparison, Comparison::Matched));
+
+        let (comparison, _) = run_compare(
+            "regex-partial",
+            Some(expected),
+            &["booted in 12ms!"],
+            false,
+            false,
+        );
+        let diff = mismatch_diff(comparison);
+        assert!(diff.contains("-re:booted in \\d+ms\n"), "{}", diff);
+        assert!(diff.contains("+booted in 12ms!\n"), "{}", diff);
+    }
+
+    #[test]
+    fn invalid_regex_is_reported_with_line_number() {
+        let dir = env::temp_dir().join(format!(
+            "bootimage-expected-output-{}-invalid",
+            process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.txt");
+        fs::write(&path, "ok\nre:(\n").unwrap();
+        let config = ExpectedOutputConfig {
+            path: path.display().to_string(),
+            ignore_unmatched: false,
+        };
+        let result = compare(&config, "test", &[], false);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(
+            result,
+            Err(ExpectedOutputError::InvalidRegex { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn escaped_lines_are_literal() {
+        let expected = "\\re:(\n\\\\path\nplain\n";
+        let output = ["re:(", "\\path", "plain"];
+        let (comparison, _) = run_compare("escaped", Some(expected), &output, false, false);
+        assert!(matches!(comparison, Comparison::Matched));
+    }
+
+    #[test]
+    fn bless_escapes_lines_and_keeps_matching_patterns() {
+        let expected = "re:t\\d+\nold\n";
+        let output = ["t5", "re:x", "\\y"];
+        let (comparison, content) = run_compare("bless", Some(expected), &output, false, true);
+        assert!(matches!(comparison, Comparison::Blessed { .. }));
+        assert_eq!(content.as_deref(), Some("re:t\\d+\n\\re:x\n\\\\y\n"));
+
+        // the blessed file matches the output
+        let (comparison, _) = run_compare("blessed", content.as_deref(), &output, false, false);
+        assert!(matches!(comparison, Comparison::Matched));
+    }
+
+    #[test]
+    fn missing_file_is_reported_or_created() {
+        let (comparison, content) = run_compare("missing", None, &["a"], false, false);
+        assert!(matches!(comparison, Comparison::Missing { .. }));
+        assert_eq!(content, None);
+
+        let (comparison, content) = run_compare("missing-bless", None, &["a"], false, true);
+        assert!(matches!(comparison, Comparison::Blessed { .. }));
+        assert_eq!(content.as_deref(), Some("a\n"));
+    }
+
+    #[test]
+    fn ignore_unmatched_skips_additional_lines() {
+        let output = ["a", "b", "c"];
+        let (comparison, _) = run_compare("ignore", Some("a\nc\n"), &output, true, false);
+        assert!(matches!(comparison, Comparison::Matched));
+
+        // the expected lines must still appear in order
+        let (comparison, _) = run_compare("ignore-order", Some("c\na\n"), &output, true, false);
+        assert_eq!(
+            hunks(&mismatch_diff(comparison)),
+            "+++ serial output\n@@ -1,2 +1,3 @@\n+a\n+b\n c\n-a\n"
+        );
+    }
+
+    #[test]
+    fn too_many_changes_are_a_full_replacement() {
+        let n = MAX_DIFF_CHANGES;
+        let edits = diff(n, n, |_, _| false);
+        assert_eq!(edits.len(), 2 * n);
+        assert!(edits[..n].iter().all(|e| matches!(e, Edit::Delete(_))));
+        assert!(edits[n..].iter().all(|e| matches!(e, Edit::Insert(_))));
+    }
+}
@@ -0,0 +1,257 @@
+//! Writes the results of test executables to a JUnit XML report.
+//!
+//! Each test executable is written as a `<testsuite>` element. Since cargo invokes the
+//! runner once per test executable, the suites of previous invocations are kept in the
+//! report. A suite with the same name as the new one is replaced, so that rerunning a test
+//! executable doesn't duplicate its results.
+//!
+//! Test executables that were rerun after a failure get `attempts` and `flaky` properties.
+//! The failures of earlier attempts are added to the affected test cases as
+//! `<flakyFailure>` elements if the test case passed in the end and as `<rerunFailure>`
+//! elements otherwise, like in the reports of Maven Surefire.
+
+use super::{
+    serial::SerialLine,
+    test_cases::{self, FailedAttempt, Outcome, TestCase, Verdict},
+};
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+/// Serializes the updates of reports when test executables are run in parallel.
+static REPORT_LOCK: Mutex<()> = Mutex::new(());
+
+/// The results of a single test executable.
+pub(crate) struct TestSuite<'a> {
+    /// The file stem of the test executable
+    pub name: &'a str,
+    /// The test cases that were reported on the serial output
+    pub cases: &'a [TestCase],
+    /// The run time of QEMU
+    pub duration: Duration,
+    /// The overall result of the test executable
+    pub verdict: &'a Verdict,
+    /// The complete serial output of the test executable
+    pub output: &'a [SerialLine],
+    /// The earlier attempts of the test executable that failed
+    pub failed_attempts: &'a [FailedAttempt],
+}
+
+/// Adds the given suite to the JUnit report at `path`, creating the report if needed.
+pub(crate) fn write_report(path: &Path, suite: &TestSuite) -> io::Result<()> {
+    let _lock = REPORT_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let existing = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err),
+    };
+    let name = escape(suite.name);
+    let mut suites: Vec<&str> = existing_suites(&existing)
+        .into_iter()
+        .filter(|s| suite_name(s) != Some(name.as_str()))
+        .collect();
+    let new_suite = suite.to_xml();
+    suites.push(&new_suite);
+
+    let mut report = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n");
+    for suite in suites {
+        report.push_str(suite);
+    }
+    report.push_str("</testsuites>\n");
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // write to a temporary file first so that the report is never left half-written
+    let tmp_path = path.with_extension("xml.tmp");
+    fs::write(&tmp_path, report)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Returns the `<testsuite>` elements of an existing report, including the trailing newline.
+///
+/// Relies on the format written by this module, i.e. each suite starts with a
+/// `  <testsuite ` line and ends with a `  </testsuite>` line. Newlines in the content are
+/// always escaped, so these lines can't occur elsewhere. Other content is discarded.
+fn existing_suites(report: &str) -> Vec<&str> {
+    const START: &str = "\n  <testsuite ";
+    const END: &str = "\n  </testsuite>\n";
+
+    let mut suites = Vec::new();
+    let mut rest = report;
+    while let Some(start) = rest.find(START) {
+        let suite = &rest[start + 1..];
+        let end = match suite.find(END) {
+            Some(end) => end + END.len(),
+            None => break,
+        };
+        suites.push(&suite[..end]);
+        rest = &suite[end - 1..];
+    }
+    suites
+}
+
+/// Returns the (escaped) value of the `name` attribute of the given suite element.
+fn suite_name(suite: &str) -> Option<&str> {
+    let start = suite.find(" name=\"")? + " name=\"".len();
+    let len = suite[start..].find('"')?;
+    Some(&suite[start..start + len])
+}
+
+impl TestSuite<'_> {
+    fn to_xml(&self) -> String {
+        let count = |outcome| self.cases.iter().filter(|c| c.outcome == outcome).count();
+        let mut failures = count(Outcome::Failed);
+        let mut skipped = count(Outcome::Ignored);
+        let executable_case = test_cases::needs_executable_case(self.cases, self.verdict);
+        if executable_case {
+            match self.verdict {
+                Verdict::Passed => {}
+                Verdict::Skipped => skipped += 1,
+                Verdict::Failed(_) => failures += 1,
+            }
+        }
+        let tests = self.cases.len() + if executable_case { 1 } else { 0 };
+
+        let mut xml = String::new();
+        let _ = writeln!(
+            xml,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"0\" skipped=\"{}\" time=\"{:.3}\">",
+            escape(self.name),
+            tests,
+            failures,
+            skipped,
+            self.duration.as_secs_f64()
+        );
+        if !self.failed_attempts.is_empty() {
+            let flaky = !matches!(self.verdict, Verdict::Failed(_));
+            let _ = writeln!(
+                xml,
+                "    <properties>\n      <property name=\"attempts\" value=\"{}\"/>\n      \
+                 <property name=\"flaky\" value=\"{}\"/>\n    </properties>",
+                self.failed_attempts.len() + 1,
+                flaky
+            );
+        }
+        for case in self.cases {
+            let mut content = String::new();
+            match case.outcome {
+                Outcome::Passed => {}
+                Outcome::Ignored => content.push_str("      <skipped/>\n"),
+                Outcome::Failed => {
+                    let message = match (self.verdict, case.finished) {
+                        (_, true) => "test case failed".to_owned(),
+                        (Verdict::Failed(failure), false) => {
+                            format!("no result reported: {}", failure)
+                        }
+                        (_, false) => "no result reported".to_owned(),
+                    };
+                    let _ = writeln!(
+                        content,
+                        "      <failure message=\"{}\">{}</failure>",
+                        escape(&message),
+                        escape(&case.output.join("\n"))
+                    );
+                }
+            }
+            let failed = case.outcome == Outcome::Failed;
+            self.write_test_case(&mut xml, Some(&case.name), case.duration, content, failed);
+        }
+        if executable_case {
+            let content = match self.verdict {
+                Verdict::Passed => String::new(),
+                Verdict::Skipped => "      <skipped/>\n".to_owned(),
+                Verdict::Failed(failure) => {
+                    format!("      <failure message=\"{}\"/>\n", escape(failure))
+                }
+            };
+            let failed = matches!(self.verdict, Verdict::Failed(_));
+            self.write_test_case(&mut xml, None, self.duration, content, failed);
+        }
+        if !self.output.is_empty() {
+            let output: Vec<_> = self.output.iter().map(|l| l.text.as_str()).collect();
+            let _ = writeln!(
+                xml,
+                "    <system-out>{}</system-out>",
+                escape(&output.join("\n"))
+            );
+        }
+        xml.push_str("  </testsuite>\n");
+        xml
+    }
+
+    /// Writes a `<testcase>` element with the given content, followed by the failures of the
+    /// test case in earlier attempts.
+    ///
+    /// `case` is the name of the reported test case or `None` for the test executable itself.
+    fn write_test_case(
+        &self,
+        xml: &mut String,
+        case: Option<&str>,
+        duration: Duration,
+        mut content: String,
+        failed: bool,
+    ) {
+        let tag = if failed {
+            "rerunFailure"
+        } else {
+            "flakyFailure"
+        };
+        for failure in test_cases::earlier_failures(self.failed_attempts, case) {
+            let _ = write!(
+                content,
+                "      <{} message=\"{}\"",
+                tag,
+                escape(&failure.message)
+            );
+            if failure.output.is_empty() {
+                content.push_str("/>\n");
+            } else {
+                let _ = writeln!(
+                    content,
+                    "><system-out>{}</system-out></{}>",
+                    escape(&failure.output),
+                    tag
+                );
+            }
+        }
+        let _ = write!(
+            xml,
+            "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+            escape(case.unwrap_or(self.name)),
+            escape(self.name),
+            duration.as_secs_f64()
+        );
+        if content.is_empty() {
+            xml.push_str("/>\n");
+        } else {
+            let _ = write!(xml, ">\n{}    </testcase>\n", content);
+        }
+    }
+}
+
+/// Escapes the given text for use in XML attributes and text nodes.
+///
+/// Control characters, which are not allowed in XML 1.0, are removed.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\n' => escaped.push_str("&#10;"),
+            '\t' => escaped.push_str("&#9;"),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,124 @@
+//! Prints the results of test executables as the JSON event stream of libtest.
+//!
+//! The events are compatible with the output of `cargo test -- --format json -Z
+//! unstable-options`, so that IDEs and other tools can consume them. Since the test cases
+//! are only parsed after QEMU exits, all events of a test executable are printed at once.
+//!
+//! If the test executable was rerun after a failure, the result events of all tests contain
+//! the number of `attempts` and the tests that failed in an earlier attempt, but not in the
+//! final one, are marked as `flaky`. These fields are not part of the libtest format.
+
+use super::{
+    serial::SerialLine,
+    test_cases::{self, FailedAttempt, Outcome, TestCase, Verdict},
+};
+use json::JsonValue;
+use std::time::Duration;
+
+/// Prints the events for a single test executable to stdout.
+///
+/// `name` is the file stem of the executable, which is used as the test name if no test
+/// cases were reported. The `verdict` is the overall result according to the exit code.
+pub(crate) fn print_events(
+    name: &str,
+    cases: &[TestCase],
+    verdict: &Verdict,
+    output: &[SerialLine],
+    duration: Duration,
+    failed_attempts: &[FailedAttempt],
+) {
+    let mark_retries = |event: &mut JsonValue, case: Option<&str>, failed: bool| {
+        if failed_attempts.is_empty() {
+            return;
+        }
+        event["attempts"] = (failed_attempts.len() + 1).into();
+        if !failed && !test_cases::earlier_failures(failed_attempts, case).is_empty() {
+            event["flaky"] = true.into();
+        }
+    };
+    let executable_case = test_cases::needs_executable_case(cases, verdict);
+    let count = |outcome| cases.iter().filter(|c| c.outcome == outcome).count();
+    let mut passed = count(Outcome::Passed);
+    let mut failed = count(Outcome::Failed);
+    let mut ignored = count(Outcome::Ignored);
+
+    let test_count = cases.len() + if executable_case { 1 } else { 0 };
+    print_event(json::object! {
+        "type" => "suite",
+        "event" => "started",
+        "test_count" => test_count,
+    });
+    for case in cases {
+        print_event(json::object! {
+            "type" => "test",
+            "event" => "started",
+            "name" => case.name.as_str(),
+        });
+        let mut event = json::object! {
+            "type" => "test",
+            "name" => case.name.as_str(),
+            "event" => match case.outcome {
+                Outcome::Passed => "ok",
+                Outcome::Failed => "failed",
+                Outcome::Ignored => "ignored",
+            },
+            "exec_time" => case.duration.as_secs_f64(),
+        };
+        if case.outcome == Outcome::Failed {
+            let mut stdout = case.output.join("\n");
+            if !case.finished {
+                stdout.push_str("\n(no result was reported for this test case)");
+            }
+            event["stdout"] = stdout.into();
+        }
+        mark_retries(
+            &mut event,
+            Some(&case.name),
+            case.outcome == Outcome::Failed,
+        );
+        print_event(event);
+    }
+    if executable_case {
+        print_event(json::object! {
+            "type" => "test",
+            "event" => "started",
+            "name" => name,
+        });
+        let mut event = json::object! {
+            "type" => "test",
+            "name" => name,
+            "event" => match verdict {
+                Verdict::Passed => "ok",
+                Verdict::Skipped => "ignored",
+                Verdict::Failed(_) => "failed",
+            },
+            "exec_time" => duration.as_secs_f64(),
+        };
+        match verdict {
+            Verdict::Passed => passed += 1,
+            Verdict::Skipped => ignored += 1,
+            Verdict::Failed(failure) => {
+                let mut stdout: Vec<_> = output.iter().map(|l| l.text.as_str()).collect();
+                stdout.push(failure);
+                event["stdout"] = stdout.join("\n").into();
+                failed += 1;
+            }
+        }
+        mark_retries(&mut event, None, matches!(verdict, Verdict::Failed(_)));
+        print_event(event);
+    }
+    print_event(json::object! {
+        "type" => "suite",
+        "event" => if let Verdict::Failed(_) = verdict { "failed" } else { "ok" },
+        "passed" => passed,
+        "failed" => failed,
+        "ignored" => ignored,
+        "measured" => 0,
+        "filtered_out" => 0,
+        "exec_time" => duration.as_secs_f64(),
+    });
+}
+
+fn print_event(event: JsonValue) {
+    println!("{}", event.dump());
+}
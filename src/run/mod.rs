@@ -0,0 +1,876 @@
+//! Provides a function for running a disk image in QEMU.
+
+use crate::{
+    args::{RunnerArgs, TestFormat},
+    builder,
+    config::{Config, QemuConfig, SerialLogConfig, TestOutcome},
+    interrupt,
+    plan::CommandDescription,
+};
+use diagnostics::QmpSocket;
+use expected_output::Comparison;
+use serial::SerialLine;
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, Instant},
+};
+use test_cases::{FailedAttempt, Outcome, Verdict};
+use thiserror::Error;
+use wait_timeout::ChildExt;
+
+/// Captures the state of QEMU before timed out tests are killed.
+mod diagnostics;
+/// Compares the serial output of tests against files with the expected output.
+mod expected_output;
+/// Writes JUnit XML reports for test executables.
+mod junit;
+/// Prints the results of test executables as libtest JSON events.
+mod libtest_json;
+/// Provides a function to capture the serial output of QEMU.
+mod serial;
+/// Parses and summarizes the results of test cases reported on the serial output.
+mod test_cases;
+
+pub use self::{diagnostics::TimeoutDiagnostics, expected_output::ExpectedOutputError};
+
+/// Run the given disk image in QEMU.
+///
+/// Automatically takes into account the runner arguments and the run/test
+/// commands defined in the given `Config`. Since test executables are treated
+/// differently (run with a timeout and match exit status), the caller needs to
+/// specify whether the given disk image is a test or not.
+///
+/// If a serial log is configured, the output of QEMU is written to a `serial.log`
+/// file in the given `log_dir` (or next to the disk image if no `log_dir` is given).
+///
+/// For test executables, the output of QEMU is parsed for test case results in the
+/// `test <name> ... ok` format of libtest and a summary is printed after QEMU exits.
+///
+/// If a JUnit report is requested through `RunnerArgs::junit` or `Config::test_report`, the
+/// results of test executables are added to it.
+///
+/// If `RunnerArgs::test_format` is `TestFormat::Json`, the results of test executables are
+/// printed as libtest JSON events on stdout and the output of QEMU is forwarded to stderr.
+///
+/// If `Config::expected_output` is set, the serial output of passing test executables is
+/// compared against the expected output file, which is updated instead if
+/// `RunnerArgs::bless` is set.
+///
+/// Failed or timed out test executables are rerun up to `RunnerArgs::retries` times (or
+/// `Config::test_retries` if not set) and reported as flaky if a rerun passes. The serial
+/// log of each attempt is kept in a separate file.
+///
+/// If `Config::test_timeout_diagnostics` is set and the run command is created by bootimage,
+/// test executables are started with a QMP socket, through which the registers and CPU
+/// state are captured before a timed out test is killed. They are written to a
+/// `timeout-diagnostics.log` file next to the serial log and attached to
+/// `RunError::TestTimedOut`.
+///
+/// QEMU is terminated when the process receives SIGINT or SIGTERM, in which case
+/// `RunError::Interrupted` is returned.
+pub fn run(
+    config: Config,
+    args: RunnerArgs,
+    image_path: &Path,
+    is_test: bool,
+    log_dir: Option<&Path>,
+) -> Result<i32, RunError> {
+    let result =
+        run_inner(config, args, image_path, is_test, log_dir, false).and_then(|(result, _)| result);
+    if interrupt::interrupted() {
+        return Err(RunError::Interrupted);
+    }
+    result
+}
+
+/// Runs the given test disk image in QEMU without printing its output, so that multiple
+/// test executables can be run in parallel.
+///
+/// Works like [`run`] for test executables, except that the output of QEMU is only
+/// collected and nothing is printed to stdout. The result is returned as a [`TestReport`]
+/// instead, which also covers timeouts. The JUnit report is written as usual; concurrent
+/// calls are serialized.
+pub fn run_test(
+    config: Config,
+    args: RunnerArgs,
+    image_path: &Path,
+    log_dir: Option<&Path>,
+) -> Result<TestReport, RunError> {
+    let result = run_inner(config, args, image_path, true, log_dir, true);
+    if interrupt::interrupted() {
+        return Err(RunError::Interrupted);
+    }
+    let (_, report) = result?;
+    Ok(report.expect("test runs always create a report"))
+}
+
+/// The result of a test executable that was run through [`run_test`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct TestReport {
+    /// The overall result of the test executable
+    pub status: TestStatus,
+    /// The number of test cases that passed in the final attempt
+    pub passed: usize,
+    /// The number of test cases that failed in the final attempt
+    pub failed: usize,
+    /// The number of test cases that were ignored in the final attempt
+    pub ignored: usize,
+    /// The names of the test cases that failed in the final attempt
+    pub failed_cases: Vec<String>,
+    /// How often the test executable was run, i.e. more than 1 if it was retried
+    pub attempts: usize,
+    /// The run time of QEMU in the final attempt
+    pub duration: Duration,
+    /// The serial output of the final attempt
+    pub output: Vec<String>,
+}
+
+impl TestReport {
+    /// Creates a report for a test executable that couldn't be run, e.g. because of an
+    /// I/O error.
+    pub fn failed(failure: String) -> Self {
+        TestReport {
+            status: TestStatus::Failed(failure),
+            passed: 0,
+            failed: 0,
+            ignored: 0,
+            failed_cases: Vec::new(),
+            attempts: 1,
+            duration: Duration::default(),
+            output: Vec::new(),
+        }
+    }
+}
+
+/// The overall result of a test executable in a [`TestReport`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TestStatus {
+    /// The test executable passed in the first attempt
+    Passed,
+    /// The exit code is configured as `skip`
+    Skipped,
+    /// The test executable passed or was skipped after earlier attempts failed
+    Flaky,
+    /// The test executable failed; contains a description of the failure
+    Failed(String),
+}
+
+/// Runs the disk image and returns the exit code of the runner.
+///
+/// For test executables, a [`TestReport`] is returned too. If `parallel` is set, the output
+/// of QEMU is only collected and nothing is printed to stdout.
+fn run_inner(
+    config: Config,
+    args: RunnerArgs,
+    image_path: &Path,
+    is_test: bool,
+    log_dir: Option<&Path>,
+    parallel: bool,
+) -> Result<(Result<i32, RunError>, Option<TestReport>), RunError> {
+    let mut run_command = command_line(&config, &args, image_path, is_test);
+    // only test executables have a timeout
+    let qmp_socket = if timeout_diagnostics(&config, is_test) {
+        QmpSocket::new()
+    } else {
+        None
+    };
+    if let Some(socket) = &qmp_socket {
+        run_command.extend(socket.qemu_args());
+    }
+    // stdout is reserved for the JSON events in this case
+    let json = is_test && !parallel && args.test_format == Some(TestFormat::Json);
+    let quiet = args.quiet || json || parallel;
+    let forward = if parallel {
+        serial::Forward::None
+    } else if json {
+        serial::Forward::Stderr
+    } else {
+        serial::Forward::Stdout
+    };
+
+    if !quiet {
+        for note in &config.notes {
+            eprintln!("Note: {}", note);
+        }
+        if let Some(notice) = accel_fallback_notice(&config) {
+            eprintln!("Note: {}", notice);
+        }
+        println!("Running: `{}`", run_command.join(" "));
+    }
+    let run_attempt = |attempt| {
+        let serial_log = if is_test {
+            config.test_serial_log.as_ref()
+        } else {
+            config.run_serial_log.as_ref()
+        };
+        let serial_log = serial_log.map(|settings| {
+            let path = log_path(image_path, log_dir, "serial", attempt);
+            (settings, path)
+        });
+        let qmp = qmp_socket
+            .as_ref()
+            .map(|socket| (socket, args.executable.as_path()));
+        run_qemu(
+            &config,
+            &run_command,
+            is_test,
+            serial_log,
+            qmp,
+            forward,
+            quiet,
+        )
+    };
+
+    if !is_test {
+        let qemu_run = run_attempt(1)?;
+        return match qemu_run.exit_status {
+            _ if interrupt::interrupted() => Err(RunError::Interrupted),
+            Some(exit_status) => {
+                let exit_code = exit_status.code().unwrap_or(1);
+                // pass the value written by the kernel through if an exit device is used
+                let guest_exit_code = config
+                    .exit_device
+                    .and_then(|device| device.guest_exit_code(exit_code));
+                Ok((Ok(guest_exit_code.unwrap_or(exit_code)), None))
+            }
+            None => unreachable!("non-test executables are run without a timeout"),
+        };
+    }
+
+    let name = args
+        .executable
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let retries = args.retries.unwrap_or(config.test_retries);
+    let mut failed_attempts = Vec::new();
+    let (result, verdict, qemu_run, test_cases) = loop {
+        let attempt = failed_attempts.len() + 1;
+        let qemu_run = run_attempt(attempt)?;
+        if interrupt::interrupted() {
+            return Err(RunError::Interrupted);
+        }
+        if let Some(diagnostics) = &qemu_run.diagnostics {
+            let path = log_path(image_path, log_dir, "timeout-diagnostics", attempt);
+            fs::write(&path, diagnostics.to_string()).map_err(context(
+                IoErrorContext::TimeoutDiagnostics { path: path.clone() },
+            ))?;
+            eprintln!(
+                "Wrote the state of QEMU at the timeout of `{}` to `{}`",
+                name,
+                path.display()
+            );
+        }
+        let test_cases = test_cases::parse(&qemu_run.output, qemu_run.duration);
+        let (result, verdict, retryable) = test_result(&config, &args, &name, &qemu_run, quiet)?;
+        match verdict {
+            Verdict::Failed(failure) if retryable && failed_attempts.len() < retries as usize => {
+                eprintln!(
+                    "Test executable `{}` failed in attempt {} of {} ({}), rerunning it",
+                    name,
+                    failed_attempts.len() + 1,
+                    retries + 1,
+                    failure
+                );
+                failed_attempts.push(FailedAttempt {
+                    cases: test_cases,
+                    failure,
+                    output: qemu_run.output,
+                    serial_log: qemu_run.serial_log,
+                });
+            }
+            verdict => break (result, verdict, qemu_run, test_cases),
+        }
+    };
+
+    let duration = qemu_run.duration;
+    if json {
+        libtest_json::print_events(
+            &name,
+            &test_cases,
+            &verdict,
+            &qemu_run.output,
+            duration,
+            &failed_attempts,
+        );
+    } else if !quiet && !test_cases.is_empty() {
+        test_cases::print_summary(&test_cases, matches!(result, Ok(0)), duration);
+    }
+    if !quiet && verdict == Verdict::Skipped {
+        println!("Test executable `{}` was skipped", name);
+    }
+    if !quiet && !failed_attempts.is_empty() {
+        test_cases::print_retries(&name, &failed_attempts, &test_cases, &verdict);
+    }
+    if let Some(path) = args.junit.as_ref().or(config.test_report.as_ref()) {
+        let suite = junit::TestSuite {
+            name: &name,
+            cases: &test_cases,
+            duration,
+            verdict: &verdict,
+            output: &qemu_run.output,
+            failed_attempts: &failed_attempts,
+        };
+        junit::write_report(path, &suite)
+            .map_err(context(IoErrorContext::TestReport { path: path.clone() }))?;
+    }
+
+    let count = |outcome| test_cases.iter().filter(|c| c.outcome == outcome).count();
+    let report = TestReport {
+        status: match verdict {
+            Verdict::Failed(failure) => TestStatus::Failed(failure),
+            _ if !failed_attempts.is_empty() => TestStatus::Flaky,
+            Verdict::Passed => TestStatus::Passed,
+            Verdict::Skipped => TestStatus::Skipped,
+        },
+        passed: count(Outcome::Passed),
+        failed: count(Outcome::Failed),
+        ignored: count(Outcome::Ignored),
+        failed_cases: test_cases
+            .iter()
+            .filter(|c| c.outcome == Outcome::Failed)
+            .map(|c| c.name.clone())
+            .collect(),
+        attempts: failed_attempts.len() + 1,
+        duration,
+        output: qemu_run.output.into_iter().map(|l| l.text).collect(),
+    };
+    Ok((result, Some(report)))
+}
+
+/// The result of a single QEMU run.
+struct QemuRun {
+    /// The exit status of QEMU, `None` if the test timed out
+    exit_status: Option<process::ExitStatus>,
+    /// The captured serial output (only collected for test executables)
+    output: Vec<SerialLine>,
+    /// The run time of QEMU
+    duration: Duration,
+    /// The path of the serial log file, if a serial log is configured
+    serial_log: Option<PathBuf>,
+    /// The state of QEMU before it was killed, if the test timed out and it could be captured
+    diagnostics: Option<TimeoutDiagnostics>,
+}
+
+/// Returns the path of the log file of the given kind (e.g. `serial`) for the given attempt
+/// (starting at 1).
+///
+/// Reruns of failed tests are written to separate files, so that the logs of all attempts
+/// are kept.
+fn log_path(image_path: &Path, log_dir: Option<&Path>, kind: &str, attempt: usize) -> PathBuf {
+    match (log_dir, attempt) {
+        (Some(log_dir), 1) => log_dir.join(format!("{}.log", kind)),
+        (Some(log_dir), attempt) => log_dir.join(format!("{}.attempt-{}.log", kind, attempt)),
+        (None, 1) => image_path.with_extension(format!("{}.log", kind)),
+        (None, attempt) => image_path.with_extension(format!("attempt-{}.{}.log", attempt, kind)),
+    }
+}
+
+/// Runs QEMU once with the given command line and captures its output.
+///
+/// If `qmp` is given, the state of QEMU is captured through the QMP socket before a timed out
+/// test is killed and resolved against the symbols of the given kernel executable.
+fn run_qemu(
+    config: &Config,
+    run_command: &[String],
+    is_test: bool,
+    serial_log: Option<(&SerialLogConfig, PathBuf)>,
+    qmp: Option<(&QmpSocket, &Path)>,
+    forward: serial::Forward,
+    quiet: bool,
+) -> Result<QemuRun, RunError> {
+    let mut command = process::Command::new(&run_command[0]);
+    command.args(&run_command[1..]);
+
+    let serial_log = match serial_log {
+        Some((settings, path)) => {
+            let file = File::create(&path)
+                .map_err(context(IoErrorContext::SerialLog { path: path.clone() }))?;
+            if !quiet {
+                println!("Writing serial output to `{}`", path.display());
+            }
+            Some((file, settings.clone(), path))
+        }
+        None => None,
+    };
+    // The output of tests is always captured to report the results of the test cases.
+    if is_test || serial_log.is_some() {
+        command.stdout(process::Stdio::piped());
+    }
+
+    let mut child = if is_test {
+        // Tests don't read from the terminal, so QEMU can be run in its own process group,
+        // which is terminated as a whole on interrupt.
+        command.stdin(process::Stdio::null());
+        interrupt::spawn(&mut command, true).map_err(|error| RunError::Io {
+            context: IoErrorContext::QemuTestCommand {
+                command: format!("{:?}", command),
+            },
+            error,
+        })?
+    } else {
+        interrupt::spawn(&mut command, false).map_err(|error| RunError::Io {
+            context: IoErrorContext::QemuRunCommand {
+                command: format!("{:?}", command),
+            },
+            error,
+        })?
+    };
+    let start = Instant::now();
+    let (log, log_path) = match serial_log {
+        Some((file, settings, path)) => (Some((file, settings)), Some(path)),
+        None => (None, None),
+    };
+    let serial_capture = child
+        .stdout
+        .take()
+        .map(|stdout| serial::capture(stdout, forward, log, is_test));
+
+    let mut diagnostics = None;
+    let exit_status = if is_test {
+        let timeout = Duration::from_secs(config.test_timeout.into());
+        let exit_status = child
+            .wait_timeout(timeout)
+            .map_err(context(IoErrorContext::WaitWithTimeout))?;
+        if exit_status.is_none() {
+            if let Some((socket, kernel)) = qmp {
+                match socket.capture(kernel) {
+                    Ok(captured) => diagnostics = Some(captured),
+                    Err(err) => {
+                        eprintln!("Failed to capture the state of QEMU through QMP: {}", err)
+                    }
+                }
+            }
+            child.kill().map_err(context(IoErrorContext::KillQemu))?;
+            child.wait().map_err(context(IoErrorContext::WaitForQemu))?;
+        }
+        exit_status
+    } else {
+        Some(child.wait().map_err(context(IoErrorContext::WaitForQemu))?)
+    };
+    let duration = start.elapsed();
+    let output = match serial_capture {
+        Some(capture) => {
+            let result = capture.join().expect("serial capture thread panicked");
+            match (result, &log_path) {
+                (Ok(lines), _) => lines,
+                (Err(error), Some(path)) => {
+                    return Err(RunError::Io {
+                        context: IoErrorContext::SerialLog { path: path.clone() },
+                        error,
+                    })
+                }
+                (Err(error), None) => {
+                    return Err(RunError::Io {
+                        context: IoErrorContext::SerialOutput,
+                        error,
+                    })
+                }
+            }
+        }
+        None => Vec::new(),
+    };
+    Ok(QemuRun {
+        exit_status,
+        output,
+        duration,
+        serial_log: log_path,
+        diagnostics,
+    })
+}
+
+/// Determines the result of a test executable from the given QEMU run.
+///
+/// Returns the exit code of the runner, the verdict for the reports, and whether a failure
+/// might go away on a rerun.
+fn test_result(
+    config: &Config,
+    args: &RunnerArgs,
+    name: &str,
+    qemu_run: &QemuRun,
+    quiet: bool,
+) -> Result<(Result<i32, RunError>, Verdict, bool), RunError> {
+    let (result, verdict) = match qemu_run.exit_status {
+        None => {
+            let diagnostics = qemu_run.diagnostics.clone().map(Box::new);
+            let mut failure = format!("test timed out after {} seconds", config.test_timeout);
+            if let Some(location) = diagnostics.as_ref().and_then(|d| d.location()) {
+                failure = format!("{} at {}", failure, location);
+            }
+            (
+                Err(RunError::TestTimedOut { diagnostics }),
+                Verdict::Failed(failure),
+            )
+        }
+        Some(exit_status) => match exit_status.code() {
+            Some(qemu_exit_code) => match config.exit_device {
+                Some(device) => match device.guest_exit_code(qemu_exit_code) {
+                    Some(code) => test_verdict(config, code, "The kernel", quiet),
+                    None => (
+                        Ok(if qemu_exit_code == 0 {
+                            1
+                        } else {
+                            qemu_exit_code
+                        }),
+                        Verdict::Failed(format!(
+                            "QEMU exited with code {} without a write to the exit device",
+                            qemu_exit_code
+                        )),
+                    ),
+                },
+                None => test_verdict(config, qemu_exit_code, "QEMU", quiet),
+            },
+            None => {
+                let failure = match termination_signal(&exit_status) {
+                    Some(signal) => {
+                        eprintln!("QEMU process was terminated by signal {}", signal);
+                        format!("QEMU was terminated by signal {}", signal)
+                    }
+                    None => "QEMU exited without an exit code".to_owned(),
+                };
+                (Err(RunError::NoQemuExitCode), Verdict::Failed(failure))
+            }
+        },
+    };
+
+    // the output is only compared if the test passed otherwise, since a diff of a crashed
+    // test is mostly noise
+    let expected = match &config.expected_output {
+        Some(expected) if verdict == Verdict::Passed => expected,
+        _ => return Ok((result, verdict, true)),
+    };
+    let test = builder::test_name_from_executable_path(&args.executable)
+        .unwrap_or_else(|| name.to_owned());
+    match expected_output::compare(expected, &test, &qemu_run.output, args.bless)? {
+        Comparison::Matched => Ok((result, verdict, true)),
+        Comparison::Blessed { path } => {
+            if !quiet {
+                println!("Updated expected output `{}`", path.display());
+            }
+            Ok((result, verdict, true))
+        }
+        Comparison::Mismatch { path, diff } => {
+            eprint!(
+                "The serial output doesn't match the expected output `{}`:\n{}",
+                path.display(),
+                diff
+            );
+            let failure = format!(
+                "serial output doesn't match the expected output `{}`",
+                path.display()
+            );
+            // the output of a test that otherwise passed is not expected to change on a
+            // rerun, and a rerun would hide the mismatch if it did
+            Ok((Ok(1), Verdict::Failed(failure), false))
+        }
+        Comparison::Missing { path } => {
+            let failure = format!(
+                "expected output file `{}` doesn't exist (run with BOOTIMAGE_BLESS=1 \
+                 to create it)",
+                path.display()
+            );
+            eprintln!("Error: {}", failure);
+            // a rerun can't create the file
+            Ok((Ok(1), Verdict::Failed(failure), false))
+        }
+    }
+}
+
+/// Determines the exit code of the runner and the verdict for the given exit code of a test
+/// executable.
+///
+/// The exit code is either the exit code of QEMU or the value that the kernel wrote to the
+/// exit device, which is described by `source` in failure messages. If neither
+/// `test-success-exit-code` nor `test-exit-codes` are configured, the exit code is passed
+/// through. Otherwise, all successful outcomes result in exit code 0 and failures result in
+/// a non-zero exit code.
+fn test_verdict(
+    config: &Config,
+    exit_code: i32,
+    source: &str,
+    quiet: bool,
+) -> (Result<i32, RunError>, Verdict) {
+    let exit_codes_configured =
+        config.test_success_exit_code.is_some() || !config.test_exit_codes.is_empty();
+    match config.test_outcome(exit_code) {
+        Some(TestOutcome::Pass) => (Ok(0), Verdict::Passed),
+        Some(TestOutcome::ExpectedPanic) => {
+            if !quiet {
+                println!("The test panicked as expected");
+            }
+            (Ok(0), Verdict::Passed)
+        }
+        Some(TestOutcome::Skip) => (Ok(0), Verdict::Skipped),
+        Some(TestOutcome::Fail) => (
+            Ok(1),
+            Verdict::Failed(format!(
+                "{} exited with code {}, which is configured as failure",
+                source, exit_code
+            )),
+        ),
+        None if !exit_codes_configured && exit_code == 0 => (Ok(0), Verdict::Passed),
+        None if !exit_codes_configured => (
+            Ok(exit_code),
+            Verdict::Failed(format!("{} exited with code {}", source, exit_code)),
+        ),
+        None => {
+            let failure = match config.test_success_exit_code {
+                Some(code) => format!(
+                    "{} exited with code {} instead of the success exit code {}",
+                    source, exit_code, code
+                ),
+                None => format!(
+                    "{} exited with code {}, which has no configured outcome",
+                    source, exit_code
+                ),
+            };
+            // exit code 0 would be interpreted as success by cargo
+            let exit_code = if exit_code == 0 { 1 } else { exit_code };
+            (Ok(exit_code), Verdict::Failed(failure))
+        }
+    }
+}
+
+/// Returns the signal that terminated the process, if any.
+#[cfg(unix)]
+fn termination_signal(exit_status: &process::ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    exit_status.signal()
+}
+
+#[cfg(not(unix))]
+fn termination_signal(_exit_status: &process::ExitStatus) -> Option<i32> {
+    None
+}
+
+/// Returns the command line that [`run`] executes for the given disk image.
+pub fn command_line(
+    config: &Config,
+    args: &RunnerArgs,
+    image_path: &Path,
+    is_test: bool,
+) -> Vec<String> {
+    let base_command = match &config.qemu {
+        Some(qemu) => qemu_command_line(qemu),
+        None => config.run_command.clone(),
+    };
+    let mut run_command: Vec<_> = base_command
+        .iter()
+        .map(|arg| arg.replace("{}", &format!("{}", image_path.display())))
+        .collect();
+    if let Some(device) = &config.exit_device {
+        run_command.extend(device.qemu_args());
+    }
+    if is_test {
+        if config.test_no_reboot {
+            run_command.push("-no-reboot".to_owned());
+        }
+        if let Some(args) = &config.test_args {
+            run_command.extend(args.iter().cloned());
+        }
+    } else if let Some(args) = &config.run_args {
+        run_command.extend(args.iter().cloned());
+    }
+    if let Some(args) = &args.runner_args {
+        run_command.extend(args.iter().cloned());
+    }
+    run_command
+}
+
+/// Creates the QEMU command line for the given `qemu` configuration table.
+///
+/// Contains a "{}" placeholder for the disk image path, like `Config::run_command`.
+fn qemu_command_line(qemu: &QemuConfig) -> Vec<String> {
+    let mut command = vec![
+        qemu.binary
+            .clone()
+            .unwrap_or_else(|| "qemu-system-x86_64".into()),
+        "-drive".into(),
+        "format=raw,file={}".into(),
+    ];
+    let (accel, cpu) = match qemu.accel.as_deref() {
+        Some("auto") if kvm_available() => (Some("kvm".into()), Some("host".into())),
+        Some("auto") => (Some("tcg".into()), None),
+        _ => (qemu.accel.clone(), None),
+    };
+    let options = [
+        ("-m", &qemu.memory),
+        ("-machine", &qemu.machine),
+        // an explicitly configured CPU model takes precedence over `-cpu host`
+        ("-cpu", if qemu.cpu.is_some() { &qemu.cpu } else { &cpu }),
+        ("-display", &qemu.display),
+        ("-serial", &qemu.serial),
+        ("-accel", &accel),
+    ];
+    for (flag, value) in options.iter() {
+        if let Some(value) = value {
+            command.push(flag.to_string());
+            command.push(value.clone());
+        }
+    }
+    if let Some(smp) = qemu.smp {
+        command.push("-smp".into());
+        command.push(smp.to_string());
+    }
+    for device in &qemu.devices {
+        command.push("-device".into());
+        command.push(device.clone());
+    }
+    command
+}
+
+/// Returns whether QEMU can use KVM acceleration, i.e. whether `/dev/kvm` exists and is
+/// accessible.
+pub fn kvm_available() -> bool {
+    cfg!(target_os = "linux")
+        && fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/kvm")
+            .is_ok()
+}
+
+/// Returns a notice if `accel = "auto"` is configured, but KVM is not available.
+pub fn accel_fallback_notice(config: &Config) -> Option<&'static str> {
+    let auto_accel = config
+        .qemu
+        .as_ref()
+        .is_some_and(|qemu| qemu.accel.as_deref() == Some("auto"));
+    if auto_accel && !kvm_available() {
+        Some("KVM is not available (`/dev/kvm` is missing or not accessible), falling back to TCG")
+    } else {
+        None
+    }
+}
+
+/// Returns whether a QMP socket is added to the run command for timeout diagnostics.
+///
+/// Custom run commands might not be QEMU or might not accept the QMP arguments, so the socket
+/// is only added to the QEMU commands that bootimage creates.
+fn timeout_diagnostics(config: &Config, is_test: bool) -> bool {
+    is_test && config.test_timeout_diagnostics && config.runs_builtin_qemu()
+}
+
+/// Describes the command that [`run`] would execute for the given disk image.
+pub fn plan(
+    config: &Config,
+    args: &RunnerArgs,
+    image_path: &Path,
+    is_test: bool,
+) -> CommandDescription {
+    let mut run_command = command_line(config, args, image_path, is_test);
+    if timeout_diagnostics(config, is_test) {
+        if let Some(socket) = QmpSocket::new() {
+            run_command.extend(socket.qemu_args());
+        }
+    }
+    let mut command = process::Command::new(&run_command[0]);
+    command.args(&run_command[1..]);
+    let phase = if is_test { "run test" } else { "run" };
+    CommandDescription::new(phase, &command, false)
+}
+
+/// Running the disk image failed.
+#[derive(Debug, Error)]
+pub enum RunError {
+    /// Test timed out
+    #[error("Test timed out{}", timeout_location(.diagnostics))]
+    TestTimedOut {
+        /// The state of QEMU before it was killed, if it could be captured
+        diagnostics: Option<Box<TimeoutDiagnostics>>,
+    },
+
+    /// Failed to read QEMU exit code
+    #[error("Failed to read QEMU exit code")]
+    NoQemuExitCode,
+
+    /// The run was interrupted by SIGINT or SIGTERM and QEMU was terminated
+    #[error("Interrupted, QEMU was terminated")]
+    Interrupted,
+
+    /// The expected output file of the test couldn't be read or written
+    #[error(transparent)]
+    ExpectedOutput(#[from] ExpectedOutputError),
+
+    /// An I/O error occured
+    #[error("{context}: An I/O error occured: {error}")]
+    Io {
+        /// The operation that caused the I/O error.
+        context: IoErrorContext,
+        /// The I/O error that occured.
+        error: io::Error,
+    },
+}
+
+/// An I/O error occured while trying to run the disk image.
+#[derive(Debug, Error)]
+pub enum IoErrorContext {
+    /// QEMU command for non-test failed
+    #[error("Failed to execute QEMU run command `{command}`")]
+    QemuRunCommand {
+        /// The QEMU command that was executed
+        command: String,
+    },
+
+    /// QEMU command for test failed
+    #[error("Failed to execute QEMU test command `{command}`")]
+    QemuTestCommand {
+        /// The QEMU command that was executed
+        command: String,
+    },
+
+    /// Waiting for test with timeout failed
+    #[error("Failed to wait with timeout")]
+    WaitWithTimeout,
+
+    /// Failed to kill QEMU
+    #[error("Failed to kill QEMU")]
+    KillQemu,
+
+    /// Failed to wait for QEMU process
+    #[error("Failed to wait for QEMU process")]
+    WaitForQemu,
+
+    /// Failed to read the serial output of QEMU
+    #[error("Failed to read the serial output of QEMU")]
+    SerialOutput,
+
+    /// Failed to write the JUnit XML report
+    #[error("Failed to write the test report `{}`", .path.display())]
+    TestReport {
+        /// The path to the report
+        path: PathBuf,
+    },
+
+    /// Failed to write the serial output to the log file
+    #[error("Failed to write serial output to `{}`", .path.display())]
+    SerialLog {
+        /// The path to the serial log file
+        path: PathBuf,
+    },
+
+    /// Failed to write the state of QEMU at a timeout
+    #[error("Failed to write the timeout diagnostics `{}`", .path.display())]
+    TimeoutDiagnostics {
+        /// The path to the diagnostics file
+        path: PathBuf,
+    },
+}
+
+/// Formats the location of a timed out test for the `RunError::TestTimedOut` message.
+fn timeout_location(diagnostics: &Option<Box<TimeoutDiagnostics>>) -> String {
+    match diagnostics.as_ref().and_then(|d| d.location()) {
+        Some(location) => format!(" at {}", location),
+        None => String::new(),
+    }
+}
+
+/// Helper function for IO error construction
+fn context(context: IoErrorContext) -> impl FnOnce(io::Error) -> RunError {
+    |error| RunError::Io { context, error }
+}
@@ -0,0 +1,235 @@
+//! Captures the serial output of QEMU.
+
+use crate::config::SerialLogConfig;
+use std::{
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    process::ChildStdout,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// A line of the serial output.
+#[derive(Debug, Clone)]
+pub(crate) struct SerialLine {
+    /// The time since the capture was started at which the first byte of the line was read
+    pub time: Duration,
+    /// The content of the line without the line ending and ANSI escape sequences
+    pub text: String,
+}
+
+/// Where the QEMU output is forwarded to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Forward {
+    Stdout,
+    /// Used when stdout is reserved for machine-readable output
+    Stderr,
+    /// The output is only collected, e.g. for tests that run in parallel
+    None,
+}
+
+/// Forwards the given QEMU output to stdout or stderr and optionally writes it to a log
+/// file.
+///
+/// The output is forwarded unchanged as soon as it is read, so that interactive output
+/// (e.g. prompts without a trailing newline) is shown immediately. The log file copy is
+/// post-processed according to the given settings.
+///
+/// If `collect_lines` is set, the returned thread yields all lines of the output.
+pub(crate) fn capture(
+    mut source: ChildStdout,
+    forward: Forward,
+    log: Option<(File, SerialLogConfig)>,
+    collect_lines: bool,
+) -> JoinHandle<io::Result<Vec<SerialLine>>> {
+    thread::spawn(move || {
+        let start = Instant::now();
+        let mut log =
+            log.map(|(file, settings)| LogWriter::new(BufWriter::new(file), settings, start));
+        let mut lines = LineCollector::new(start);
+        let mut buf = [0; 4096];
+        loop {
+            let len = match source.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            let data = &buf[..len];
+            match forward {
+                Forward::Stdout => {
+                    let mut stdout = io::stdout();
+                    stdout.write_all(data)?;
+                    stdout.flush()?;
+                }
+                Forward::Stderr => io::stderr().write_all(data)?,
+                Forward::None => {}
+            }
+            if let Some(log) = &mut log {
+                log.write(data)?;
+            }
+            if collect_lines {
+                lines.push(data);
+            }
+        }
+        if let Some(log) = log {
+            log.finish()?;
+        }
+        Ok(lines.finish())
+    })
+}
+
+/// Splits the serial output into lines.
+struct LineCollector {
+    start: Instant,
+    lines: Vec<SerialLine>,
+    current: Vec<u8>,
+    current_time: Duration,
+    ansi: AnsiParser,
+}
+
+impl LineCollector {
+    fn new(start: Instant) -> Self {
+        LineCollector {
+            start,
+            lines: Vec::new(),
+            current: Vec::new(),
+            current_time: Duration::default(),
+            ansi: AnsiParser::default(),
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        for &byte in data {
+            if self.ansi.skip(byte) {
+                continue;
+            }
+            if byte == b'\n' {
+                self.finish_line();
+                continue;
+            }
+            if self.current.is_empty() {
+                self.current_time = self.start.elapsed();
+            }
+            self.current.push(byte);
+        }
+    }
+
+    fn finish_line(&mut self) {
+        let mut text = String::from_utf8_lossy(&self.current).into_owned();
+        if text.ends_with('\r') {
+            text.pop();
+        }
+        self.lines.push(SerialLine {
+            time: self.current_time,
+            text,
+        });
+        self.current.clear();
+    }
+
+    fn finish(mut self) -> Vec<SerialLine> {
+        if !self.current.is_empty() {
+            self.finish_line();
+        }
+        self.lines
+    }
+}
+
+/// The state of the ANSI escape sequence parser.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum AnsiState {
+    Text,
+    /// After an `ESC` byte
+    Escape,
+    /// Inside a control sequence (`ESC [`)
+    Csi,
+    /// Inside an operating system command (`ESC ]`)
+    Osc,
+    /// After an `ESC` byte inside an operating system command
+    OscEscape,
+}
+
+/// Recognizes ANSI escape sequences in a byte stream.
+struct AnsiParser {
+    state: AnsiState,
+}
+
+impl Default for AnsiParser {
+    fn default() -> Self {
+        AnsiParser {
+            state: AnsiState::Text,
+        }
+    }
+}
+
+impl AnsiParser {
+    /// Updates the parser with the given byte and returns whether the byte is part of an
+    /// escape sequence.
+    fn skip(&mut self, byte: u8) -> bool {
+        const ESC: u8 = 0x1b;
+        const BEL: u8 = 0x07;
+
+        let (next_state, skip) = match (self.state, byte) {
+            (AnsiState::Text, ESC) => (AnsiState::Escape, true),
+            (AnsiState::Text, _) => (AnsiState::Text, false),
+            (AnsiState::Escape, b'[') => (AnsiState::Csi, true),
+            (AnsiState::Escape, b']') => (AnsiState::Osc, true),
+            // other escape sequences consist of a single byte after the `ESC`
+            (AnsiState::Escape, _) => (AnsiState::Text, true),
+            // control sequences are terminated by a byte in the range `@` to `~`
+            (AnsiState::Csi, 0x40..=0x7e) => (AnsiState::Text, true),
+            (AnsiState::Csi, _) => (AnsiState::Csi, true),
+            (AnsiState::Osc, BEL) => (AnsiState::Text, true),
+            (AnsiState::Osc, ESC) => (AnsiState::OscEscape, true),
+            (AnsiState::Osc, _) => (AnsiState::Osc, true),
+            (AnsiState::OscEscape, _) => (AnsiState::Text, true),
+        };
+        self.state = next_state;
+        skip
+    }
+}
+
+/// Writes the serial output to the log file, adding timestamps and removing ANSI escape
+/// sequences if configured.
+struct LogWriter<W> {
+    out: W,
+    settings: SerialLogConfig,
+    start: Instant,
+    at_line_start: bool,
+    ansi: AnsiParser,
+}
+
+impl<W: Write> LogWriter<W> {
+    fn new(out: W, settings: SerialLogConfig, start: Instant) -> Self {
+        LogWriter {
+            out,
+            settings,
+            start,
+            at_line_start: true,
+            ansi: AnsiParser::default(),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut processed = Vec::with_capacity(data.len());
+        for &byte in data {
+            if self.settings.strip_ansi && self.ansi.skip(byte) {
+                continue;
+            }
+            if self.at_line_start && self.settings.timestamps {
+                let elapsed = self.start.elapsed().as_secs_f64();
+                processed.extend_from_slice(format!("[{:>12.6}] ", elapsed).as_bytes());
+            }
+            processed.push(byte);
+            self.at_line_start = byte == b'\n';
+        }
+        self.out.write_all(&processed)
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        if !self.at_line_start {
+            self.out.write_all(b"\n")?;
+        }
+        self.out.flush()
+    }
+}
@@ -0,0 +1,290 @@
+//! Parses the results of individual test cases from the serial output.
+//!
+//! Kernels can report their test cases by printing lines in the format of libtest:
+//!
+//! ```text
+//! test <name> ... ok
+//! test <name> ... FAILED
+//! test <name> ... ignored
+//! ```
+//!
+//! The result can also be printed on a later line than the `test <name> ... ` prefix, in
+//! which case all lines in between are recorded as the output of the test case. Test cases
+//! without a result (e.g. because the kernel panicked) are treated as failed.
+
+use super::serial::SerialLine;
+use std::{path::PathBuf, time::Duration};
+
+/// The outcome of a test case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    Passed,
+    Failed,
+    Ignored,
+}
+
+impl Outcome {
+    fn parse(s: &str) -> Option<Self> {
+        match s.trim() {
+            "ok" => Some(Outcome::Passed),
+            "FAILED" => Some(Outcome::Failed),
+            s if s == "ignored" || s.starts_with("ignored,") => Some(Outcome::Ignored),
+            _ => None,
+        }
+    }
+}
+
+/// The overall result of a test executable, which is determined by the exit code of QEMU.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Passed,
+    /// The exit code is configured as `skip`
+    Skipped,
+    /// Contains a description of the failure
+    Failed(String),
+}
+
+/// A test case that was reported on the serial output.
+#[derive(Debug, Clone)]
+pub(crate) struct TestCase {
+    pub name: String,
+    pub outcome: Outcome,
+    /// The lines that were printed between the start and the result of the test case
+    pub output: Vec<String>,
+    /// The time between the start and the result of the test case
+    pub duration: Duration,
+    /// Whether a result line was printed for the test case
+    pub finished: bool,
+}
+
+/// A failed attempt of a test executable that was rerun afterwards.
+#[derive(Debug)]
+pub(crate) struct FailedAttempt {
+    /// The test cases that were reported in the attempt
+    pub cases: Vec<TestCase>,
+    /// The description of the failure
+    pub failure: String,
+    /// The serial output of the attempt
+    pub output: Vec<SerialLine>,
+    /// The serial log file of the attempt, if a serial log is configured
+    pub serial_log: Option<PathBuf>,
+}
+
+/// A failure of a test case in an earlier attempt.
+pub(crate) struct EarlierFailure {
+    pub message: String,
+    pub output: String,
+}
+
+/// Returns the failures of a test case in the given earlier attempts.
+///
+/// `case` is the name of a reported test case or `None` for the test executable itself,
+/// which stands for the failures of attempts that aren't explained by a failed test case.
+pub(crate) fn earlier_failures(
+    attempts: &[FailedAttempt],
+    case: Option<&str>,
+) -> Vec<EarlierFailure> {
+    let mut failures = Vec::new();
+    for attempt in attempts {
+        match case {
+            Some(name) => {
+                let failed = attempt
+                    .cases
+                    .iter()
+                    .find(|c| c.name == name && c.outcome == Outcome::Failed);
+                if let Some(failed) = failed {
+                    let message = if failed.finished {
+                        "test case failed".to_owned()
+                    } else {
+                        format!("no result reported: {}", attempt.failure)
+                    };
+                    failures.push(EarlierFailure {
+                        message,
+                        output: failed.output.join("\n"),
+                    });
+                }
+            }
+            None => {
+                let verdict = Verdict::Failed(attempt.failure.clone());
+                if needs_executable_case(&attempt.cases, &verdict) {
+                    let output: Vec<_> = attempt.output.iter().map(|l| l.text.as_str()).collect();
+                    failures.push(EarlierFailure {
+                        message: attempt.failure.clone(),
+                        output: output.join("\n"),
+                    });
+                }
+            }
+        }
+    }
+    failures
+}
+
+/// Parses the test cases from the given serial output.
+///
+/// `end` is the time at which QEMU exited, which is used as the end time of an unfinished
+/// test case.
+pub(crate) fn parse(lines: &[SerialLine], end: Duration) -> Vec<TestCase> {
+    let mut cases = Vec::new();
+    let mut current: Option<(TestCase, Duration)> = None;
+    for line in lines {
+        if let Some(rest) = line.text.strip_prefix("test ") {
+            if let Some((name, result)) = rest.split_once(" ... ") {
+                if let Some((unfinished, start)) = current.take() {
+                    cases.push(finish(unfinished, start, line.time, None));
+                }
+                let case = TestCase {
+                    name: name.trim().to_owned(),
+                    outcome: Outcome::Failed,
+                    output: Vec::new(),
+                    duration: Duration::default(),
+                    finished: false,
+                };
+                match Outcome::parse(result) {
+                    Some(outcome) => cases.push(finish(case, line.time, line.time, Some(outcome))),
+                    None => {
+                        let mut case = case;
+                        if !result.trim().is_empty() {
+                            case.output.push(result.to_owned());
+                        }
+                        current = Some((case, line.time));
+                    }
+                }
+                continue;
+            }
+        }
+        if let Some((mut case, start)) = current.take() {
+            match Outcome::parse(&line.text) {
+                Some(outcome) => cases.push(finish(case, start, line.time, Some(outcome))),
+                None => {
+                    case.output.push(line.text.clone());
+                    current = Some((case, start));
+                }
+            }
+        }
+    }
+    if let Some((unfinished, start)) = current {
+        cases.push(finish(unfinished, start, end, None));
+    }
+    cases
+}
+
+fn finish(
+    mut case: TestCase,
+    start: Duration,
+    end: Duration,
+    outcome: Option<Outcome>,
+) -> TestCase {
+    case.duration = end.saturating_sub(start);
+    case.finished = outcome.is_some();
+    case.outcome = outcome.unwrap_or(Outcome::Failed);
+    case
+}
+
+/// Returns whether the overall result of a test executable needs to be reported as a
+/// separate test case in the machine-readable reports.
+///
+/// This is the case if no test cases were reported, if the executable was skipped, or if it
+/// failed without a failed test case that explains it.
+pub(crate) fn needs_executable_case(cases: &[TestCase], verdict: &Verdict) -> bool {
+    match verdict {
+        _ if cases.is_empty() => true,
+        Verdict::Passed => false,
+        Verdict::Skipped => true,
+        Verdict::Failed(_) => cases.iter().all(|c| c.outcome != Outcome::Failed),
+    }
+}
+
+/// Prints a libtest-style summary of the given test cases.
+///
+/// The overall result is determined by the exit code of QEMU (passed as `success`), not by
+/// the test cases.
+pub(crate) fn print_summary(cases: &[TestCase], success: bool, duration: Duration) {
+    let count = |outcome| cases.iter().filter(|c| c.outcome == outcome).count();
+    let failed: Vec<_> = cases
+        .iter()
+        .filter(|c| c.outcome == Outcome::Failed)
+        .collect();
+
+    println!();
+    if !failed.is_empty() {
+        println!("failures:");
+        println!();
+        for case in &failed {
+            println!("---- {} output ----", case.name);
+            for line in &case.output {
+                println!("{}", line);
+            }
+            if !case.finished {
+                println!("(no result was reported for this test case)");
+            }
+            println!();
+        }
+        println!("failures:");
+        for case in &failed {
+            println!("    {}", case.name);
+        }
+        println!();
+    }
+    println!(
+        "test result: {}. {} passed; {} failed; {} ignored; finished in {:.2}s",
+        if success { "ok" } else { "FAILED" },
+        count(Outcome::Passed),
+        failed.len(),
+        count(Outcome::Ignored),
+        duration.as_secs_f64()
+    );
+    if success && !failed.is_empty() {
+        println!(
+            "note: the test executable exited with the success exit code even though {} \
+             test case(s) failed",
+            failed.len()
+        );
+    }
+    println!();
+}
+
+/// Prints which attempts of a retried test executable failed.
+///
+/// The test executable is flaky if the final attempt didn't fail. In this case, the test
+/// cases that failed in an earlier attempt, but not in the final one, are listed too.
+pub(crate) fn print_retries(
+    name: &str,
+    attempts: &[FailedAttempt],
+    cases: &[TestCase],
+    verdict: &Verdict,
+) {
+    let total = attempts.len() + 1;
+    match verdict {
+        Verdict::Failed(_) => println!(
+            "Test executable `{}` failed in all {} attempts, the earlier ones with:",
+            name, total
+        ),
+        _ => println!(
+            "Test executable `{}` is flaky: it passed in attempt {} of {} after these failures:",
+            name, total, total
+        ),
+    }
+    for (i, attempt) in attempts.iter().enumerate() {
+        match &attempt.serial_log {
+            Some(path) => println!(
+                "    attempt {}: {} (serial log: `{}`)",
+                i + 1,
+                attempt.failure,
+                path.display()
+            ),
+            None => println!("    attempt {}: {}", i + 1, attempt.failure),
+        }
+    }
+    let flaky: Vec<_> = cases
+        .iter()
+        .filter(|c| c.outcome != Outcome::Failed)
+        .filter(|c| !earlier_failures(attempts, Some(&c.name)).is_empty())
+        .collect();
+    if !flaky.is_empty() {
+        println!("flaky test cases:");
+        for case in flaky {
+            println!("    {}", case.name);
+        }
+    }
+    println!();
+}
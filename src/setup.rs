@@ -0,0 +1,142 @@
+//! Writes the runner and build configuration into a kernel project.
+
+use crate::config;
+use anyhow::{anyhow, Context, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use toml_edit::{Array, Document, Item, Key, Table, TableLike};
+
+/// The `target` table key (in TOML syntax) that is used for the `runner` key in the cargo
+/// configuration.
+const RUNNER_CFG_KEY: &str = r#"'cfg(target_os = "none")'"#;
+
+/// Writes the configuration that is needed for `cargo bootimage` and `bootimage runner` into
+/// the kernel crate at the given manifest path.
+///
+/// This sets the `runner` and `build-std` keys in the `.cargo/config.toml` file of the crate
+/// (or `.cargo/config` if only that exists) and adds an empty `package.metadata.bootimage`
+/// table to the `Cargo.toml`. Existing keys and formatting are kept, only the `runner` key
+/// is overwritten. If a `target` is given, it is written to the `build.target` key.
+///
+/// Returns the paths of all files that were modified.
+pub fn setup(manifest_path: &Path, target: Option<&str>) -> Result<Vec<PathBuf>> {
+    let kernel_dir = manifest_path
+        .parent()
+        .ok_or_else(|| anyhow!("kernel manifest path has no parent directory"))?;
+    let mut modified = Vec::new();
+
+    let cargo_config_path = cargo_config_path(kernel_dir);
+    if update_file(&cargo_config_path, |doc| update_cargo_config(doc, target))? {
+        modified.push(cargo_config_path);
+    }
+    if update_file(manifest_path, update_manifest)? {
+        modified.push(manifest_path.to_owned());
+    }
+
+    config::read_config(manifest_path).context("Configuration is invalid after setup")?;
+
+    Ok(modified)
+}
+
+/// Returns the path to the cargo configuration file of the given directory.
+///
+/// Prefers `.cargo/config.toml`, but uses the legacy `.cargo/config` file if only that exists.
+fn cargo_config_path(kernel_dir: &Path) -> PathBuf {
+    let cargo_dir = kernel_dir.join(".cargo");
+    let legacy = cargo_dir.join("config");
+    let path = cargo_dir.join("config.toml");
+    if !path.exists() && legacy.is_file() {
+        legacy
+    } else {
+        path
+    }
+}
+
+/// Applies `update` to the TOML document at `path` and writes it back if it changed.
+///
+/// Missing files are treated as empty documents. Returns whether the file was written.
+fn update_file<F>(path: &Path, update: F) -> Result<bool>
+where
+    F: FnOnce(&mut Document) -> Result<()>,
+{
+    let content = if path.exists() {
+        fs::read_to_string(path).with_context(|| format!("Failed to read `{}`", path.display()))?
+    } else {
+        String::new()
+    };
+    let mut doc = content
+        .parse::<Document>()
+        .with_context(|| format!("Failed to parse `{}`", path.display()))?;
+    update(&mut doc).with_context(|| format!("Failed to update `{}`", path.display()))?;
+
+    let new_content = doc.to_string();
+    if new_content == content {
+        return Ok(false);
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory `{}`", parent.display()))?;
+    }
+    fs::write(path, new_content)
+        .with_context(|| format!("Failed to write `{}`", path.display()))?;
+    Ok(true)
+}
+
+fn update_cargo_config(doc: &mut Document, target: Option<&str>) -> Result<()> {
+    let root = doc.as_table_mut();
+
+    if let Some(target) = target {
+        table_mut(root, "build", false)?.insert("target", toml_edit::value(target));
+    }
+
+    let unstable = table_mut(root, "unstable", false)?;
+    if !unstable.contains_key("build-std") {
+        let crates: Array = ["core", "compiler_builtins"].iter().copied().collect();
+        unstable.insert("build-std", toml_edit::value(crates));
+    }
+    if !unstable.contains_key("build-std-features") {
+        let features: Array = ["compiler-builtins-mem"].iter().copied().collect();
+        unstable.insert("build-std-features", toml_edit::value(features));
+    }
+
+    let targets = table_mut(root, "target", true)?;
+    table_mut(targets, RUNNER_CFG_KEY, false)?
+        .insert("runner", toml_edit::value("bootimage runner"));
+
+    Ok(())
+}
+
+fn update_manifest(doc: &mut Document) -> Result<()> {
+    let package = doc
+        .get_mut("package")
+        .and_then(Item::as_table_like_mut)
+        .ok_or_else(|| anyhow!("manifest has no `[package]` table"))?;
+    let metadata = table_mut(package, "metadata", true)?;
+    table_mut(metadata, "bootimage", false)?;
+    Ok(())
+}
+
+/// Returns the table at `key`, inserting a new one if it doesn't exist.
+///
+/// The `key` is given in TOML syntax so that quoted keys keep their quoting style when they
+/// are inserted. Newly inserted tables are marked as implicit if `implicit` is set, so that no header is
+/// written for them if they only contain subtables.
+fn table_mut<'a>(
+    parent: &'a mut dyn TableLike,
+    key: &str,
+    implicit: bool,
+) -> Result<&'a mut dyn TableLike> {
+    let key = match Key::parse(key)?.as_slice() {
+        [key] => key.clone(),
+        _ => return Err(anyhow!("`{}` is not a single TOML key", key)),
+    };
+    let mut table = Table::new();
+    table.set_implicit(implicit);
+    parent
+        .entry_format(&key)
+        .or_insert(Item::Table(table))
+        .as_table_like_mut()
+        .ok_or_else(|| anyhow!("`{}` key exists but is not a table", key))
+}